@@ -0,0 +1,258 @@
+use crate::{error::ServerError, rate_limit::RateLimit, utils::unix_timestamp};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::sync::{Mutex, RwLock};
+
+/// An API key and the customer it belongs to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ApiKey {
+    pub(crate) key: String,
+    /// Name of the customer owning the key
+    pub(crate) name: String,
+    /// Unix timestamp of the creation of the key
+    #[serde(default)]
+    pub(crate) created: u64,
+    /// Rate limit of the key, overriding the default per-key limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rate_limit: Option<RateLimit>,
+}
+impl ApiKey {
+    /// Returns a description of the key that is safe to log or to list.
+    pub(crate) fn summary(&self) -> ApiKeySummary {
+        ApiKeySummary {
+            key: mask_key(&self.key),
+            name: self.name.clone(),
+            created: self.created,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+/// An API key with its secret masked
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ApiKeySummary {
+    pub(crate) key: String,
+    pub(crate) name: String,
+    pub(crate) created: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rate_limit: Option<RateLimit>,
+}
+
+/// Identity resolved from the API key of a request. It is attached to the request extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct ApiKeyIdentity {
+    /// The API key
+    pub(crate) key: String,
+    /// Name of the customer owning the key
+    pub(crate) name: String,
+    /// The masked key
+    pub(crate) key_hint: String,
+    /// Rate limit of the key, overriding the default per-key limit
+    pub(crate) rate_limit: Option<RateLimit>,
+}
+
+/// Contents of the API key file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ApiKeyFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// API keys accepted on the image endpoints, backed by a JSON file
+#[derive(Debug)]
+pub(crate) struct ApiKeyStore {
+    path: PathBuf,
+    keys: RwLock<HashMap<String, ApiKey>>,
+    // serializes the writers so that concurrent updates never interleave
+    lock: Mutex<()>,
+}
+impl ApiKeyStore {
+    /// Loads the API keys from `path`. A missing file is treated as an empty store.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> Result<Self, ServerError> {
+        let path = path.into();
+
+        let file: ApiKeyFile = match path.exists() {
+            true => {
+                let content = std::fs::read(&path).map_err(|e| {
+                    ServerError::ArgumentError(format!(
+                        "failed to read the API key file {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+                serde_json::from_slice(&content).map_err(|e| {
+                    ServerError::ArgumentError(format!(
+                        "failed to parse the API key file {}: {}",
+                        path.display(),
+                        e
+                    ))
+                })?
+            }
+            false => {
+                warn!(target: "stdout", "API key file {} does not exist yet, every request will be rejected until a key is added", path.display());
+                ApiKeyFile::default()
+            }
+        };
+
+        for key in &file.keys {
+            if let Some(rate_limit) = &key.rate_limit {
+                rate_limit.validate().map_err(|e| {
+                    ServerError::ArgumentError(format!(
+                        "invalid rate limit of the API key {} in {}: {}",
+                        mask_key(&key.key),
+                        path.display(),
+                        e
+                    ))
+                })?;
+            }
+        }
+
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|key| (key.key.clone(), key))
+            .collect::<HashMap<_, _>>();
+        info!(target: "stdout", "loaded {} API keys from {}", keys.len(), path.display());
+
+        Ok(Self {
+            path,
+            keys: RwLock::new(keys),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Resolves the identity of an API key, or returns `None` if the key is unknown.
+    pub(crate) async fn resolve(&self, key: &str) -> Option<ApiKeyIdentity> {
+        let keys = self.keys.read().await;
+        let entry = keys
+            .values()
+            .find(|entry| crate::auth::constant_time_eq(entry.key.as_bytes(), key.as_bytes()))?;
+
+        Some(ApiKeyIdentity {
+            key: entry.key.clone(),
+            name: entry.name.clone(),
+            key_hint: mask_key(&entry.key),
+            rate_limit: entry.rate_limit,
+        })
+    }
+
+    /// Adds an API key. A random key is generated if `key` is `None`.
+    pub(crate) async fn add(
+        &self,
+        name: &str,
+        key: Option<String>,
+        rate_limit: Option<RateLimit>,
+    ) -> Result<ApiKey, ServerError> {
+        let key = key.unwrap_or_else(|| format!("sk-{}", uuid::Uuid::new_v4().simple()));
+        if key.trim().is_empty() || name.trim().is_empty() {
+            return Err(ServerError::Operation(
+                "the name and the key must not be empty".to_string(),
+            ));
+        }
+        if let Some(rate_limit) = &rate_limit {
+            rate_limit.validate().map_err(ServerError::Operation)?;
+        }
+
+        let api_key = ApiKey {
+            key,
+            name: name.to_string(),
+            created: unix_timestamp(),
+            rate_limit,
+        };
+
+        {
+            let mut keys = self.keys.write().await;
+            if keys.contains_key(&api_key.key) {
+                return Err(ServerError::Operation("the key already exists".to_string()));
+            }
+            keys.insert(api_key.key.clone(), api_key.clone());
+        }
+        self.save().await?;
+
+        info!(target: "stdout", "added API key {} for {}", mask_key(&api_key.key), api_key.name);
+
+        Ok(api_key)
+    }
+
+    /// Revokes an API key. Returns the revoked key.
+    pub(crate) async fn revoke(&self, key: &str) -> Result<Vec<ApiKey>, ServerError> {
+        let removed = self.keys.write().await.remove(key);
+        let removed = removed.ok_or_else(|| {
+            ServerError::Operation(format!("the key {} does not exist", mask_key(key)))
+        })?;
+        self.save().await?;
+
+        info!(target: "stdout", "revoked API key {} of {}", mask_key(&removed.key), removed.name);
+
+        Ok(vec![removed])
+    }
+
+    /// Revokes every API key of a customer. Returns the revoked keys.
+    pub(crate) async fn revoke_by_name(&self, name: &str) -> Result<Vec<ApiKey>, ServerError> {
+        let removed: Vec<ApiKey> = {
+            let mut keys = self.keys.write().await;
+            let matched: Vec<String> = keys
+                .values()
+                .filter(|key| key.name == name)
+                .map(|key| key.key.clone())
+                .collect();
+            matched.iter().filter_map(|key| keys.remove(key)).collect()
+        };
+        if removed.is_empty() {
+            return Err(ServerError::Operation(format!(
+                "no key belongs to {}",
+                name
+            )));
+        }
+        self.save().await?;
+
+        info!(target: "stdout", "revoked {} API keys of {}", removed.len(), name);
+
+        Ok(removed)
+    }
+
+    /// Lists the API keys with their secrets masked.
+    pub(crate) async fn list(&self) -> Vec<ApiKeySummary> {
+        let mut keys: Vec<ApiKeySummary> = self
+            .keys
+            .read()
+            .await
+            .values()
+            .map(|key| key.summary())
+            .collect();
+        keys.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+
+        keys
+    }
+
+    async fn save(&self) -> Result<(), ServerError> {
+        let _lock = self.lock.lock().await;
+
+        let mut keys: Vec<ApiKey> = self.keys.read().await.values().cloned().collect();
+        keys.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+
+        let content = serde_json::to_vec_pretty(&ApiKeyFile { keys })
+            .map_err(|e| ServerError::Operation(e.to_string()))?;
+
+        crate::utils::write_atomically(&self.path, &content).map_err(|e| {
+            ServerError::Operation(format!(
+                "failed to write the API key file {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+}
+
+/// Masks an API key for logging, keeping only a few characters at both ends.
+pub(crate) fn mask_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    match chars.len() {
+        0..=8 => "*".repeat(chars.len()),
+        len => format!(
+            "{}...{}",
+            chars[..3].iter().collect::<String>(),
+            chars[len - 4..].iter().collect::<String>()
+        ),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    api_keys::{mask_key, ApiKeyIdentity},
+    error, AppState,
+};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::AUTHORIZATION, HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+
+/// Checks the admin token on every `/admin` route.
+///
+/// The routes are left open if no admin token is configured.
+pub(crate) async fn admin_auth<B>(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(admin_token) = state.admin_token.as_deref() {
+        match bearer_token(req.headers()) {
+            Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {}
+            Some(_) => {
+                warn!(target: "stdout", "rejected admin request {} {} from {}: invalid admin token", req.method(), req.uri().path(), addr);
+                return error::unauthorized("Invalid admin token.", "invalid_admin_token")
+                    .into_response();
+            }
+            None => {
+                warn!(target: "stdout", "rejected admin request {} {} from {}: missing admin token", req.method(), req.uri().path(), addr);
+                return error::unauthorized(
+                    "Missing admin token. Provide it as `Authorization: Bearer <token>`.",
+                    "missing_admin_token",
+                )
+                .into_response();
+            }
+        }
+    }
+
+    info!(target: "stdout", "admin request {} {} from {}", req.method(), req.uri().path(), addr);
+
+    next.run(req).await
+}
+
+/// Checks the API key on the image endpoints and attaches the resolved `ApiKeyIdentity` to
+/// the request.
+///
+/// The endpoints accept anonymous requests if no API key file is configured.
+pub(crate) async fn api_key_auth<B>(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) => api_keys,
+        None => return next.run(req).await,
+    };
+
+    let identity = match bearer_token(req.headers()) {
+        Some(key) => match api_keys.resolve(key).await {
+            Some(identity) => identity,
+            None => {
+                warn!(target: "stdout", "rejected request {} from {}: unknown API key {}", req.uri().path(), addr, mask_key(key));
+                return error::unauthorized(
+                    format!(
+                        "Incorrect API key provided: {}. Ask the administrator of the proxy for a valid key.",
+                        mask_key(key)
+                    ),
+                    "invalid_api_key",
+                )
+                .into_response();
+            }
+        },
+        None => {
+            warn!(target: "stdout", "rejected request {} from {}: missing API key", req.uri().path(), addr);
+            return error::unauthorized(
+                "You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY).",
+                "missing_api_key",
+            )
+            .into_response();
+        }
+    };
+
+    info!(target: "stdout", "request {} from {} authenticated as {} ({})", req.uri().path(), addr, identity.name, identity.key_hint);
+    req.extensions_mut().insert(identity);
+
+    next.run(req).await
+}
+
+/// Applies the global and per API key rate limits to the endpoints starting a generation.
+///
+/// It runs after `api_key_auth`, so the identity of the request is already resolved.
+pub(crate) async fn rate_limit<B>(
+    State(state): State<AppState>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let key = req.extensions().get::<ApiKeyIdentity>().map(|identity| {
+        (
+            identity.key.as_str(),
+            identity.name.as_str(),
+            identity.rate_limit,
+        )
+    });
+
+    let permit = match state.rate_limiter.acquire(key) {
+        Ok(permit) => permit,
+        Err(e) => return error::too_many_requests(e.message, e.retry_after).into_response(),
+    };
+
+    // the permit is released with the request, unless the handler hands it over to a job
+    req.extensions_mut().insert(permit);
+
+    next.run(req).await
+}
+
+/// Extracts the token of an `Authorization: Bearer <token>` header.
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    match scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        true => Some(token.trim()),
+        false => None,
+    }
+}
+
+/// Compares two secrets in time that does not depend on where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
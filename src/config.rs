@@ -0,0 +1,467 @@
+use crate::{
+    error::ServerError,
+    output::{self, MetadataPolicy},
+    rate_limit::RateLimit,
+    s3::S3Config,
+    PolicyKind,
+};
+use hyper::Uri;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Configuration file of SD-Proxy-Server. Every field is optional, and the command line
+/// options take precedence over the values in the file.
+///
+/// The file is parsed as JSON if its extension is `.json`, and as TOML otherwise.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Socket address to listen on, e.g. `0.0.0.0:8080`
+    pub(crate) listen: Option<SocketAddr>,
+    /// Path to the state file of the downstream server registry
+    pub(crate) state_file: Option<PathBuf>,
+    /// Policy used to pick a downstream server
+    pub(crate) routing_policy: Option<PolicyKind>,
+    /// Token required on the `/admin` endpoints
+    pub(crate) admin_token: Option<String>,
+    /// Path to the JSON file of the API keys accepted on the image endpoints
+    pub(crate) api_keys_file: Option<PathBuf>,
+    /// Respond to the image endpoints with a bare array of images instead of the OpenAI envelope
+    pub(crate) legacy_response: Option<bool>,
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub(crate) retry: RetrySection,
+    #[serde(default)]
+    pub(crate) health_check: HealthCheckSection,
+    #[serde(default)]
+    pub(crate) discovery: DiscoverySection,
+    #[serde(default)]
+    pub(crate) backends: BackendsConfig,
+    #[serde(default)]
+    pub(crate) rate_limit: RateLimitSection,
+    #[serde(default)]
+    pub(crate) queue: QueueSection,
+    #[serde(default)]
+    pub(crate) jobs: JobsSection,
+    #[serde(default)]
+    pub(crate) image_store: ImageStoreSection,
+    #[serde(default)]
+    pub(crate) output: OutputSection,
+}
+
+/// Timeouts in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TimeoutsConfig {
+    /// Timeout of a single attempt to a downstream server
+    pub(crate) request: Option<u64>,
+    /// Total time budget for all the attempts of a single request, including the time spent in the queue
+    pub(crate) retry_budget: Option<u64>,
+    /// Timeout of a single health check
+    pub(crate) health_check: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetrySection {
+    /// Maximum number of downstream servers tried for a single request
+    pub(crate) max_attempts: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HealthCheckSection {
+    /// Interval in seconds between health checks. `0` disables health checking.
+    pub(crate) interval: Option<u64>,
+    pub(crate) unhealthy_threshold: Option<u64>,
+    pub(crate) healthy_threshold: Option<u64>,
+}
+
+/// Discovery of the capabilities of the downstream servers
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DiscoverySection {
+    /// Interval in seconds between two refreshes. `0` queries the servers only on registration.
+    pub(crate) interval: Option<u64>,
+}
+
+/// Proxy-side queue of the requests waiting for a free downstream server
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QueueSection {
+    /// Maximum number of waiting requests
+    pub(crate) max_depth: Option<usize>,
+    /// Maximum time in seconds a request waits
+    pub(crate) max_wait: Option<u64>,
+    /// Maximum number of requests in flight on every server that does not declare its own
+    pub(crate) server_max_concurrency: Option<u64>,
+}
+
+/// Asynchronous generation jobs
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JobsSection {
+    /// Time in seconds the result of a finished job is kept
+    pub(crate) ttl: Option<u64>,
+}
+
+/// Store of the images returned with `response_format: "url"`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ImageStoreSection {
+    /// Directory of the stored images. The store is disabled if it is not set.
+    pub(crate) dir: Option<PathBuf>,
+    /// Base of the URLs of the stored images, e.g. `https://images.example.com`
+    pub(crate) public_base_url: Option<String>,
+    /// Time in seconds the images are kept
+    pub(crate) retention: Option<u64>,
+    /// S3-compatible bucket used instead of the directory
+    pub(crate) s3: Option<S3Section>,
+}
+
+/// S3-compatible bucket of the image store
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3Section {
+    /// Endpoint of the object storage, e.g. `http://localhost:9000`. There is no TLS client, so
+    /// https endpoints such as AWS S3 are not supported.
+    pub(crate) endpoint: String,
+    /// Region of the bucket. Defaults to `us-east-1`.
+    pub(crate) region: Option<String>,
+    pub(crate) bucket: String,
+    /// Prefix of the object keys, e.g. `images/`
+    #[serde(default)]
+    pub(crate) prefix: String,
+    /// Defaults to the `AWS_ACCESS_KEY_ID` environment variable
+    pub(crate) access_key_id: Option<String>,
+    /// Defaults to the `AWS_SECRET_ACCESS_KEY` environment variable
+    pub(crate) secret_access_key: Option<String>,
+    /// Base of the public URLs of the objects. Presigned URLs are returned if it is not set.
+    pub(crate) public_base_url: Option<String>,
+    /// Validity in seconds of the presigned URLs, at most 7 days. Defaults to `retention`.
+    pub(crate) presign_expiry: Option<u64>,
+}
+
+/// Post-processing of the returned images
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutputSection {
+    /// What to do with the textual metadata of the PNG images
+    pub(crate) png_metadata: Option<MetadataPolicy>,
+    /// Keywords and texts written in the PNG images with `png_metadata = "replace"`
+    #[serde(default)]
+    pub(crate) png_metadata_text: BTreeMap<String, String>,
+    /// Add the generation info, e.g. the seed, to every returned image
+    pub(crate) generation_info: Option<bool>,
+}
+
+/// Rate limits of the image endpoints
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimitSection {
+    /// Limit shared by all the clients
+    #[serde(default)]
+    pub(crate) global: RateLimit,
+    /// Limit of every API key that does not declare its own
+    #[serde(default)]
+    pub(crate) per_key: RateLimit,
+}
+
+/// Downstream servers registered at startup, grouped by type
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackendsConfig {
+    #[serde(default)]
+    pub(crate) image: Vec<BackendConfig>,
+    /// Routing policy of every type of servers, overriding `routing_policy`
+    #[serde(default)]
+    pub(crate) routing_policy: BackendPolicies,
+}
+
+/// Routing policies by type of servers
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackendPolicies {
+    pub(crate) image: Option<PolicyKind>,
+}
+
+/// A downstream server and its options
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackendConfig {
+    pub(crate) url: String,
+    /// Timeout in seconds of a single attempt to this server. Overrides `timeouts.request`.
+    pub(crate) request_timeout: Option<u64>,
+    /// Maximum number of requests in flight on this server. Overrides `queue.server_max_concurrency`.
+    pub(crate) max_concurrency: Option<u64>,
+    /// Checkpoints served by this server, in addition to the discovered ones
+    #[serde(default)]
+    pub(crate) models: Vec<String>,
+    /// Share of the traffic of this server under the weighted routing policies. Defaults to 1.
+    pub(crate) weight: Option<u32>,
+}
+
+impl Config {
+    /// Loads and validates a configuration file.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            ServerError::ArgumentError(format!(
+                "failed to read the config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let config: Config = match is_json {
+            true => serde_json::from_str(&content).map_err(|e| e.to_string()),
+            false => toml::from_str(&content).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| {
+            ServerError::ArgumentError(format!(
+                "failed to parse the config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ServerError> {
+        if self
+            .admin_token
+            .as_deref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            return Err(ServerError::ArgumentError(
+                "`admin_token` must not be empty".to_string(),
+            ));
+        }
+        if self.retry.max_attempts == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`retry.max_attempts` must be at least 1".to_string(),
+            ));
+        }
+        if self.health_check.unhealthy_threshold == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`health_check.unhealthy_threshold` must be at least 1".to_string(),
+            ));
+        }
+        if self.health_check.healthy_threshold == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`health_check.healthy_threshold` must be at least 1".to_string(),
+            ));
+        }
+        if self.timeouts.request == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`timeouts.request` must be greater than 0".to_string(),
+            ));
+        }
+        if self.timeouts.retry_budget == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`timeouts.retry_budget` must be greater than 0".to_string(),
+            ));
+        }
+        if self.timeouts.health_check == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`timeouts.health_check` must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.queue.max_depth == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`queue.max_depth` must be greater than 0".to_string(),
+            ));
+        }
+        if self.queue.max_wait == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`queue.max_wait` must be greater than 0".to_string(),
+            ));
+        }
+        if self.queue.server_max_concurrency == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`queue.server_max_concurrency` must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.jobs.ttl == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`jobs.ttl` must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.image_store.dir.is_some() && self.image_store.s3.is_some() {
+            return Err(ServerError::ArgumentError(
+                "`image_store.dir` and `image_store.s3` cannot be used together".to_string(),
+            ));
+        }
+        if let Some(s3) = &self.image_store.s3 {
+            s3.to_s3_config(Duration::from_secs(1))?;
+        }
+        if self.image_store.retention == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`image_store.retention` must be greater than 0".to_string(),
+            ));
+        }
+
+        for keyword in self.output.png_metadata_text.keys() {
+            output::validate_png_keyword(keyword)
+                .map_err(|e| ServerError::ArgumentError(format!("`output`: {}", e)))?;
+        }
+
+        self.rate_limit
+            .global
+            .validate()
+            .map_err(|e| ServerError::ArgumentError(format!("`rate_limit.global`: {}", e)))?;
+        self.rate_limit
+            .per_key
+            .validate()
+            .map_err(|e| ServerError::ArgumentError(format!("`rate_limit.per_key`: {}", e)))?;
+
+        let mut urls = HashSet::new();
+        for backend in &self.backends.image {
+            backend.uri()?;
+
+            if backend.request_timeout == Some(0) {
+                return Err(ServerError::ArgumentError(format!(
+                    "`request_timeout` of the backend {} must be greater than 0",
+                    backend.url
+                )));
+            }
+            if backend.weight == Some(0) {
+                return Err(ServerError::ArgumentError(format!(
+                    "`weight` of the backend {} must be at least 1",
+                    backend.url
+                )));
+            }
+            if backend.max_concurrency == Some(0) {
+                return Err(ServerError::ArgumentError(format!(
+                    "`max_concurrency` of the backend {} must be greater than 0",
+                    backend.url
+                )));
+            }
+            if !urls.insert(backend.url.trim_end_matches('/')) {
+                return Err(ServerError::ArgumentError(format!(
+                    "the backend {} is declared more than once",
+                    backend.url
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl S3Section {
+    /// Resolves the options of the bucket. `default_expiry` is the validity of the presigned
+    /// URLs if `presign_expiry` is not set.
+    pub(crate) fn to_s3_config(&self, default_expiry: Duration) -> Result<S3Config, ServerError> {
+        let endpoint: Uri = self.endpoint.parse().map_err(|e| {
+            ServerError::ArgumentError(format!(
+                "invalid `image_store.s3.endpoint` `{}`: {}",
+                self.endpoint, e
+            ))
+        })?;
+        if endpoint.scheme_str() != Some("http") || endpoint.authority().is_none() {
+            return Err(ServerError::ArgumentError(format!(
+                "invalid `image_store.s3.endpoint` `{}`: expected an http url such as `http://localhost:9000`, https endpoints are not supported",
+                self.endpoint
+            )));
+        }
+        if self.bucket.trim().is_empty() {
+            return Err(ServerError::ArgumentError(
+                "`image_store.s3.bucket` must not be empty".to_string(),
+            ));
+        }
+        if self.presign_expiry == Some(0) {
+            return Err(ServerError::ArgumentError(
+                "`image_store.s3.presign_expiry` must be greater than 0".to_string(),
+            ));
+        }
+
+        let credential = |value: &Option<String>, var: &str| {
+            value
+                .clone()
+                .or_else(|| std::env::var(var).ok())
+                .ok_or_else(|| {
+                    ServerError::ArgumentError(format!(
+                        "no S3 credentials: set `image_store.s3.{}` or the `{}` environment variable",
+                        var.to_lowercase().trim_start_matches("aws_"),
+                        var
+                    ))
+                })
+        };
+
+        Ok(S3Config {
+            endpoint,
+            region: self
+                .region
+                .clone()
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            bucket: self.bucket.clone(),
+            prefix: self.prefix.clone(),
+            access_key_id: credential(&self.access_key_id, "AWS_ACCESS_KEY_ID")?,
+            secret_access_key: credential(&self.secret_access_key, "AWS_SECRET_ACCESS_KEY")?,
+            public_base_url: self.public_base_url.clone(),
+            presign_expiry: self
+                .presign_expiry
+                .map(Duration::from_secs)
+                .unwrap_or(default_expiry),
+        })
+    }
+}
+
+impl BackendConfig {
+    pub(crate) fn uri(&self) -> Result<Uri, ServerError> {
+        let uri: Uri = self.url.parse().map_err(|e| {
+            ServerError::ArgumentError(format!("invalid backend url `{}`: {}", self.url, e))
+        })?;
+        if uri.scheme().is_none() || uri.authority().is_none() {
+            return Err(ServerError::ArgumentError(format!(
+                "invalid backend url `{}`: expected an absolute url such as `http://localhost:7860`",
+                self.url
+            )));
+        }
+
+        Ok(uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<(), ServerError> {
+        let config: Config = toml::from_str(content).unwrap();
+        config.validate()
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        for (content, field) in [
+            ("[queue]\nmax_depth = 0", "`queue.max_depth`"),
+            ("[queue]\nmax_wait = 0", "`queue.max_wait`"),
+            ("[timeouts]\nretry_budget = 0", "`timeouts.retry_budget`"),
+            ("[timeouts]\nhealth_check = 0", "`timeouts.health_check`"),
+        ] {
+            match parse(content) {
+                Err(ServerError::ArgumentError(msg)) => assert!(msg.contains(field), "{}", msg),
+                other => panic!("{} is accepted: {:?}", field, other),
+            }
+        }
+
+        assert!(parse("[queue]\nmax_depth = 1\nmax_wait = 1").is_ok());
+    }
+}
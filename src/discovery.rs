@@ -0,0 +1,308 @@
+use crate::{utils::unix_timestamp, AppState, Server, SharedClient};
+use hyper::{body::to_bytes, Uri};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+/// Options of SD WebUI, holding the checkpoint currently loaded in `sd_model_checkpoint`
+const OPTIONS_ENDPOINT: &str = "sdapi/v1/options";
+/// Checkpoints SD WebUI can load
+const SD_MODELS_ENDPOINT: &str = "sdapi/v1/sd-models";
+const SAMPLERS_ENDPOINT: &str = "sdapi/v1/samplers";
+const LORAS_ENDPOINT: &str = "sdapi/v1/loras";
+const UPSCALERS_ENDPOINT: &str = "sdapi/v1/upscalers";
+/// Preprocessors of the ControlNet extension, missing if the extension is not installed
+const CONTROLNET_MODULES_ENDPOINT: &str = "controlnet/module_list";
+/// Timeout of a single discovery request
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checkpoints of a downstream server
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ServerModels {
+    /// Checkpoint currently loaded, e.g. `v1-5-pruned-emaonly.safetensors [6ce0161689]`
+    pub(crate) loaded: Option<String>,
+    /// Checkpoints the server can load, declared at registration or discovered
+    pub(crate) available: Vec<String>,
+}
+impl ServerModels {
+    /// Whether nothing is known about the checkpoints of the server
+    pub(crate) fn is_unknown(&self) -> bool {
+        self.loaded.is_none() && self.available.is_empty()
+    }
+}
+
+/// Samplers, LoRAs, upscalers and ControlNet modules of a downstream server. A list is `None`
+/// until it is discovered, or if the server does not expose it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Capabilities {
+    pub(crate) samplers: Option<Vec<String>>,
+    pub(crate) loras: Option<Vec<String>>,
+    pub(crate) upscalers: Option<Vec<String>>,
+    pub(crate) controlnet_modules: Option<Vec<String>>,
+    /// Unix timestamp of the last discovery, or `None` if the server has never been queried
+    pub(crate) discovered_at: Option<u64>,
+}
+
+/// How well a downstream server serves the checkpoint requested by a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ModelMatch {
+    /// The checkpoint is loaded
+    Loaded,
+    /// The checkpoint can be loaded, at the cost of a switch
+    Available,
+    /// The checkpoints of the server are unknown
+    Unknown,
+}
+
+impl Server {
+    /// Tells how well the server serves `model`, or `None` if it does not serve it.
+    pub(crate) fn match_model(&self, model: &str) -> Option<ModelMatch> {
+        let models = self.models.read().unwrap();
+        let key = model_key(model);
+
+        if models
+            .loaded
+            .as_deref()
+            .is_some_and(|loaded| model_key(loaded) == key)
+        {
+            Some(ModelMatch::Loaded)
+        } else if models.available.iter().any(|name| model_key(name) == key) {
+            Some(ModelMatch::Available)
+        } else if models.is_unknown() {
+            Some(ModelMatch::Unknown)
+        } else {
+            None
+        }
+    }
+
+    /// Records the checkpoint loaded by a generation that requested it.
+    pub(crate) fn set_loaded_model(&self, model: &str) {
+        let mut models = self.models.write().unwrap();
+        if models.loaded.as_deref() != Some(model) {
+            info!(target: "stdout", "server {} switched to the model {}", self.url, model);
+            models.loaded = Some(model.to_string());
+        }
+    }
+}
+
+/// Spawns a task that asks a newly registered downstream server for its capabilities.
+pub(crate) fn spawn_discovery(client: SharedClient, server: Arc<Server>) {
+    tokio::spawn(async move { discover(&client, &server).await });
+}
+
+/// Spawns a task that periodically refreshes the capabilities of every registered downstream
+/// server. Nothing is refreshed if `interval` is zero.
+pub(crate) fn spawn_discovery_refresher(state: AppState, interval: Duration) {
+    if interval.is_zero() {
+        info!(target: "stdout", "capability refresh is disabled");
+        return;
+    }
+
+    info!(target: "stdout", "refreshing the capabilities every {} seconds", interval.as_secs());
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // the servers are queried on registration
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+
+            let servers: Vec<Arc<Server>> =
+                state.image_urls.read().await.servers.read().await.clone();
+
+            let discoveries = servers
+                .iter()
+                .filter(|server| server.is_healthy())
+                .map(|server| discover(&state.client, server));
+            futures_util::future::join_all(discoveries).await;
+        }
+    });
+}
+
+/// Queries the checkpoints and the capabilities of a downstream server. The lists the server
+/// fails to return keep their previous value.
+async fn discover(client: &SharedClient, server: &Server) {
+    let (num_models, samplers, loras, upscalers, controlnet_modules) = futures_util::join!(
+        refresh_models(client, server),
+        get_json(client, &server.url, SAMPLERS_ENDPOINT),
+        get_json(client, &server.url, LORAS_ENDPOINT),
+        get_json(client, &server.url, UPSCALERS_ENDPOINT),
+        get_json(client, &server.url, CONTROLNET_MODULES_ENDPOINT),
+    );
+
+    let mut capabilities = server.capabilities.write().unwrap();
+    let update = |list: &mut Option<Vec<String>>, value: Option<Vec<String>>| {
+        if value.is_some() {
+            *list = value;
+        }
+    };
+    update(
+        &mut capabilities.samplers,
+        samplers.as_ref().and_then(|v| names(v, "name")),
+    );
+    update(
+        &mut capabilities.loras,
+        loras.as_ref().and_then(|v| names(v, "name")),
+    );
+    update(
+        &mut capabilities.upscalers,
+        upscalers.as_ref().and_then(|v| names(v, "name")),
+    );
+    update(
+        &mut capabilities.controlnet_modules,
+        controlnet_modules
+            .as_ref()
+            .and_then(|v| v.get("module_list"))
+            .and_then(|v| v.as_array())
+            .map(|modules| {
+                modules
+                    .iter()
+                    .filter_map(|module| module.as_str())
+                    .map(String::from)
+                    .collect()
+            }),
+    );
+    capabilities.discovered_at = Some(unix_timestamp());
+
+    let count = |list: &Option<Vec<String>>| list.as_ref().map_or(0, Vec::len);
+
+    info!(target: "stdout", "discovered the capabilities of {}: {} models, {} samplers, {} loras, {} upscalers, {} controlnet modules", server.url, num_models, count(&capabilities.samplers), count(&capabilities.loras), count(&capabilities.upscalers), count(&capabilities.controlnet_modules));
+}
+
+/// Queries the loaded checkpoint and the available checkpoints of a downstream server. The
+/// checkpoints declared at registration are always kept. Returns the number of available
+/// checkpoints.
+pub(crate) async fn refresh_models(client: &SharedClient, server: &Server) -> usize {
+    let (options, sd_models) = futures_util::join!(
+        get_json(client, &server.url, OPTIONS_ENDPOINT),
+        get_json(client, &server.url, SD_MODELS_ENDPOINT),
+    );
+
+    let mut models = server.models.write().unwrap();
+    if let Some(loaded) = options
+        .as_ref()
+        .and_then(|options| options.get("sd_model_checkpoint"))
+        .and_then(|v| v.as_str())
+    {
+        models.loaded = Some(loaded.to_string());
+    }
+    if let Some(discovered) = sd_models.as_ref().and_then(|v| names(v, "title")) {
+        let mut available = server.declared_models.clone();
+        for model in discovered {
+            if !available
+                .iter()
+                .any(|name| model_key(name) == model_key(&model))
+            {
+                available.push(model);
+            }
+        }
+        models.available = available;
+    }
+
+    models.available.len()
+}
+
+/// Reads the `field` of every object of a JSON array, e.g. the `name` of every sampler.
+fn names(value: &serde_json::Value, field: &str) -> Option<Vec<String>> {
+    value.as_array().map(|items| {
+        items
+            .iter()
+            .filter_map(|item| item.get(field).and_then(|v| v.as_str()))
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Sends a GET request to a downstream server and parses the JSON response. Failures are
+/// logged and return `None`.
+async fn get_json(
+    client: &SharedClient,
+    server: &Uri,
+    endpoint: &str,
+) -> Option<serde_json::Value> {
+    let uri: Uri = format!("{}/{}", server.to_string().trim_end_matches('/'), endpoint)
+        .parse()
+        .ok()?;
+
+    let response = match tokio::time::timeout(DISCOVERY_TIMEOUT, client.get(uri.clone())).await {
+        Ok(Ok(response)) if response.status().is_success() => response,
+        Ok(Ok(response)) => {
+            warn!(target: "stdout", "discovery: {} returned {}", uri, response.status());
+            return None;
+        }
+        Ok(Err(e)) => {
+            warn!(target: "stdout", "discovery: {} failed: {}", uri, e);
+            return None;
+        }
+        Err(_) => {
+            warn!(target: "stdout", "discovery: {} timed out", uri);
+            return None;
+        }
+    };
+
+    let body = to_bytes(response.into_body()).await.ok()?;
+    match serde_json::from_slice(&body) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!(target: "stdout", "discovery: failed to parse the response of {}: {}", uri, e);
+            None
+        }
+    }
+}
+
+/// Normalizes the name of a checkpoint, so that the title
+/// `v1-5-pruned-emaonly.safetensors [6ce0161689]`, the file `models/v1-5-pruned-emaonly.ckpt`
+/// and the name `v1-5-pruned-emaonly` all designate the same checkpoint.
+pub(crate) fn model_key(name: &str) -> String {
+    let name = name.trim();
+    // the hash of the title
+    let name = match name.rfind(" [") {
+        Some(index) if name.ends_with(']') => &name[..index],
+        _ => name,
+    };
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    let name = [".safetensors", ".ckpt", ".pt", ".bin", ".pth"]
+        .iter()
+        .find_map(|extension| name.strip_suffix(extension))
+        .unwrap_or(name);
+
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_names_are_normalized() {
+        for name in [
+            "v1-5-pruned-emaonly",
+            "v1-5-pruned-emaonly.safetensors",
+            "v1-5-pruned-emaonly.safetensors [6ce0161689]",
+            "models/Stable-diffusion/v1-5-pruned-emaonly.ckpt",
+            "C:\\sd\\models\\v1-5-pruned-emaonly.pt",
+            "  V1-5-Pruned-EMAonly.safetensors  ",
+        ] {
+            assert_eq!(model_key(name), "v1-5-pruned-emaonly", "{}", name);
+        }
+
+        // only a trailing hash and a known extension are removed
+        assert_eq!(model_key("sd_xl_base_1.0"), "sd_xl_base_1.0");
+        assert_eq!(model_key("model [v2] final"), "model [v2] final");
+        assert_eq!(model_key("anime.onnx"), "anime.onnx");
+        assert_ne!(model_key("anime-v2"), model_key("anime"));
+    }
+
+    #[test]
+    fn servers_are_ranked_by_their_checkpoints() {
+        let server = Server::new("http://a:7860".parse().unwrap());
+        assert_eq!(server.match_model("anime"), Some(ModelMatch::Unknown));
+
+        let server = server.with_models(vec!["anime.safetensors [31e35c80fc]".into()]);
+        assert_eq!(server.match_model("anime"), Some(ModelMatch::Available));
+        assert_eq!(server.match_model("photo"), None);
+
+        server.set_loaded_model("photo.ckpt");
+        assert_eq!(server.match_model("photo"), Some(ModelMatch::Loaded));
+        assert_eq!(server.match_model("ANIME"), Some(ModelMatch::Available));
+    }
+}
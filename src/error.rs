@@ -75,17 +75,132 @@ pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
         .unwrap()
 }
 
+#[allow(dead_code)]
+pub(crate) fn unauthorized(msg: impl AsRef<str>, code: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "401 Unauthorized".to_string(),
+        false => format!("401 Unauthorized: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    json_error(
+        hyper::StatusCode::UNAUTHORIZED,
+        msg.as_ref(),
+        "invalid_request_error",
+        code.as_ref(),
+    )
+}
+
+#[allow(dead_code)]
+pub(crate) fn too_many_requests(msg: impl AsRef<str>, retry_after: u64) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "429 Too Many Requests".to_string(),
+        false => format!("429 Too Many Requests: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    let mut response = json_error(
+        hyper::StatusCode::TOO_MANY_REQUESTS,
+        msg.as_ref(),
+        "requests",
+        "rate_limit_exceeded",
+    );
+    response
+        .headers_mut()
+        .insert(hyper::header::RETRY_AFTER, retry_after.into());
+
+    response
+}
+
+#[allow(dead_code)]
+pub(crate) fn service_unavailable(msg: impl AsRef<str>, retry_after: u64) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "503 Service Unavailable".to_string(),
+        false => format!("503 Service Unavailable: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    let mut response = json_error(
+        hyper::StatusCode::SERVICE_UNAVAILABLE,
+        msg.as_ref(),
+        "server_error",
+        "server_overloaded",
+    );
+    response
+        .headers_mut()
+        .insert(hyper::header::RETRY_AFTER, retry_after.into());
+
+    response
+}
+
+#[allow(dead_code)]
+pub(crate) fn not_found(msg: impl AsRef<str>, code: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "404 Not Found".to_string(),
+        false => format!("404 Not Found: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    json_error(
+        hyper::StatusCode::NOT_FOUND,
+        msg.as_ref(),
+        "invalid_request_error",
+        code.as_ref(),
+    )
+}
+
+/// Builds an error response with an OpenAI-style JSON body.
+fn json_error(status: hyper::StatusCode, msg: &str, err_type: &str, code: &str) -> Response<Body> {
+    let body = serde_json::json!({
+        "error": {
+            "message": msg,
+            "type": err_type,
+            "param": null,
+            "code": code,
+        }
+    });
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
 #[allow(dead_code)]
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ServerError {
     #[error("Not found available server")]
     NotFoundServer,
+    /// Error returned when no healthy server serves the requested checkpoint
+    #[error("No available server serves the model `{0}`")]
+    ModelNotServed(String),
+    /// Error returned when too many requests are waiting for a free server
+    #[error("All the servers are busy and the request queue is full")]
+    QueueFull,
+    /// Error returned when a request waited too long for a free server
+    #[error("All the servers are busy and the request timed out in the queue")]
+    QueueTimeout,
     /// Error returned while parsing socket address failed
     #[error("Failed to parse socket address: {0}")]
     SocketAddr(String),
     /// Error returned while parsing CLI options failed
     #[error("{0}")]
     ArgumentError(String),
+    /// Error returned while loading or saving the state file
+    #[error("State file error: {0}")]
+    StateFile(String),
     /// Generic error returned while performing an operation
     #[error("{0}")]
     Operation(String),
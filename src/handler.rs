@@ -1,36 +1,61 @@
-use crate::{error, AppState, RoutingPolicy, SharedClient, UrlType};
+use crate::{
+    api_keys::{ApiKeyIdentity, ApiKeySummary},
+    error::{self, ServerError},
+    images::{self, GeneratedImage, ImageEditRequest, ImageOutput, ImagesResponse, ResponseFormat},
+    output::{self, MetadataPolicy},
+    progress,
+    rate_limit::{RateLimit, RatePermit},
+    utils::unix_timestamp,
+    AppState, ConnectionGuard, PolicyKind, RouteContext, RoutingPolicy, Server, SharedClient,
+    UrlType,
+};
 use axum::{
     body::Body,
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::{Request, Response, StatusCode, Uri},
 };
 use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
 use endpoints::images::{sd_webui::Txt2ImgRequest, ImageObject};
-use hyper::{body::to_bytes, Method};
-use std::{fs::File, io::Read};
+use hyper::{
+    body::to_bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    Method,
+};
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::File,
+    io::Read,
+    time::{Duration, Instant},
+};
+
+/// Seconds a client is asked to wait when all the downstream servers are busy
+const QUEUE_RETRY_AFTER: u64 = 5;
+/// Header asking for the legacy bare array of images instead of the OpenAI envelope
+const LEGACY_RESPONSE_HEADER: &str = "x-sd-proxy-legacy-response";
+/// Endpoint of SD WebUI that stops the current generation
+const INTERRUPT_ENDPOINT: &str = "sdapi/v1/interrupt";
+/// Timeout of an interrupt request
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub(crate) async fn image_handler(
     State(state): State<AppState>,
     req: Request<Body>,
 ) -> Result<Response<Body>, StatusCode> {
-    info!(target: "stdout", "handling image request");
-
-    let image_url = match state.image_urls.read().await.next().await {
-        Ok(url) => url,
-        Err(e) => {
-            let err_msg = e.to_string();
-            info!(target: "stdout", "{}", &err_msg);
-            return Ok(error::internal_server_error(&err_msg));
+    match req.extensions().get::<ApiKeyIdentity>() {
+        Some(identity) => {
+            info!(target: "stdout", "handling image request from {} ({})", identity.name, identity.key_hint)
         }
-    };
+        None => info!(target: "stdout", "handling image request"),
+    }
 
-    proxy_request(state.client, req, image_url).await
+    proxy_request(state, req).await
 }
 
 pub(crate) async fn proxy_request(
-    client: SharedClient,
+    state: AppState,
     mut req: Request<Body>,
-    downstream_url: Uri,
 ) -> Result<Response<Body>, StatusCode> {
     if req.method().eq(&hyper::http::Method::OPTIONS) {
         let result = Response::builder()
@@ -54,255 +79,1884 @@ pub(crate) async fn proxy_request(
     }
 
     // Change the request URL to the downstream server
-    let endpoint = req
-        .uri()
-        .path_and_query()
-        .map(|x| x.to_string())
-        .unwrap_or_default();
+    let endpoint = req.uri().path().to_string();
     info!(target: "stdout", "endpoint: {}", endpoint);
 
-    if &endpoint == "/v1/images/generations" {
-        let image_request = if req.method() == Method::POST {
-            info!(target: "stdout", "Prepare the image generation request.");
+    let kind = match endpoint.as_str() {
+        "/v1/images/generations" => GenerationKind::Txt2Img,
+        "/v1/images/edits" => GenerationKind::Img2Img,
+        _ => return Ok(error::invalid_endpoint(&endpoint)),
+    };
+
+    let task = match GenerationTask::from_request(&state, kind, &mut req).await {
+        Ok(task) => task,
+        Err(response) => return Ok(response),
+    };
 
-            // parse request
-            let body_bytes = match to_bytes(req.body_mut()).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
+    if is_async(req.uri()) {
+        let permit = take_permit(&mut req);
+        return Ok(submit_job(state, task, owner(&req), permit));
+    }
+    if task.stream {
+        let owner = owner(&req);
+        let permit = take_permit(&mut req);
+        let id = start_job(&state, task, owner.clone(), permit);
+        return Ok(progress::job_events_response(state, id, owner, true));
+    }
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    match run_generation(&state, &task, &|_| {}).await {
+        Ok(images) => {
+            let response_body = match state.legacy_response || wants_legacy_response(&req) {
+                // a bare array of images, as returned by the earlier versions
+                true => serde_json::to_string(&images),
+                false => serde_json::to_string(&ImagesResponse {
+                    created: unix_timestamp(),
+                    data: images,
+                }),
+            }
+            .unwrap();
 
-                    return Ok(error::internal_server_error(err_msg));
-                }
-            };
-            let image_request: Txt2ImgRequest = match serde_json::from_slice(&body_bytes) {
-                Ok(image_request) => image_request,
-                Err(e) => {
-                    let err_msg =
-                        format!("Fail to deserialize image create request: {msg}", msg = e);
+            // create a response with status code 200. Content-Type is JSON
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(response_body))
+                .unwrap();
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            Ok(response)
+        }
+        Err(e) => Ok(e.into_response()),
+    }
+}
 
-                    return Ok(error::bad_request(err_msg));
-                }
-            };
+/// Downstream endpoint a generation request is translated to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GenerationKind {
+    /// `/v1/images/generations`, served by `sdapi/v1/txt2img`
+    Txt2Img,
+    /// `/v1/images/edits`, served by `sdapi/v1/img2img`
+    Img2Img,
+}
+impl GenerationKind {
+    fn sdapi_endpoint(&self) -> &'static str {
+        match self {
+            GenerationKind::Txt2Img => "sdapi/v1/txt2img",
+            GenerationKind::Img2Img => "sdapi/v1/img2img",
+        }
+    }
+}
 
-            image_request
-        } else {
+/// A generation request translated for the downstream server
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationTask {
+    kind: GenerationKind,
+    /// Request body of the downstream endpoint
+    body: Bytes,
+    prompt: String,
+    /// Checkpoint requested in `override_settings.sd_model_checkpoint`
+    model: Option<String>,
+    /// Whether the server keeps the checkpoint loaded after the generation, i.e.
+    /// `override_settings_restore_afterwards` is false
+    keeps_model: bool,
+    /// Whether the client asked for the progress as Server-Sent Events
+    stream: bool,
+    output: ImageOutput,
+}
+impl GenerationTask {
+    /// Reads and translates the body of a generation request, or returns the error response
+    /// to send back to the client.
+    async fn from_request(
+        state: &AppState,
+        kind: GenerationKind,
+        req: &mut Request<Body>,
+    ) -> Result<Self, Response<Body>> {
+        if req.method() != Method::POST {
             let err_msg = "Invalid HTTP Method.";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return Ok(error::internal_server_error(err_msg));
+            return Err(error::internal_server_error(err_msg));
+        }
+
+        // parse request
+        let body_bytes = match to_bytes(req.body_mut()).await {
+            Ok(body_bytes) => body_bytes,
+            Err(e) => {
+                let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(error::internal_server_error(err_msg));
+            }
         };
 
-        let body = serde_json::to_string(&image_request).unwrap();
+        let mut stream = false;
+        let mut model = None;
+        let mut keeps_model = false;
+        let mut output = ImageOutput::default();
+        let (body, prompt) = match kind {
+            GenerationKind::Txt2Img => {
+                info!(target: "stdout", "Prepare the image generation request.");
 
-        let mut server_socket_addr = downstream_url.to_string();
-        server_socket_addr = server_socket_addr.trim_end_matches('/').to_string();
+                let mut value: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        let err_msg =
+                            format!("Fail to deserialize image create request: {msg}", msg = e);
 
-        let downstream_uri: Uri = format!("{}/sdapi/v1/txt2img", server_socket_addr)
-            .parse()
-            .unwrap();
-        info!(target: "stdout", "dispatch the request to {}", downstream_uri);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-        // create a request to the downstream server
-        let downstream_request = Request::builder()
-            .method("POST")
-            .uri(downstream_uri)
-            .body(Body::from(body))
-            .unwrap();
+                        return Err(error::bad_request(err_msg));
+                    }
+                };
+                if let Some(object) = value.as_object_mut() {
+                    stream = object
+                        .remove("stream")
+                        .and_then(|stream| stream.as_bool())
+                        .unwrap_or_default();
+
+                    model = object
+                        .get("override_settings")
+                        .and_then(|settings| settings.get("sd_model_checkpoint"))
+                        .and_then(|model| model.as_str())
+                        .filter(|model| !model.trim().is_empty())
+                        .map(String::from);
+                    // SD WebUI restores the previous settings by default
+                    keeps_model = !object
+                        .get("override_settings_restore_afterwards")
+                        .and_then(|restore| restore.as_bool())
+                        .unwrap_or(true);
+
+                    // accept the fields of the OpenAI `CreateImageRequest` as well
+                    output = match images::translate_create_image_request(object) {
+                        Ok(output) => output,
+                        Err(e) => {
+                            let err_msg = format!("Invalid image create request: {msg}", msg = e);
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-        // Forward the request to the downstream server
-        match client.request(downstream_request).await {
-            Ok(mut response) => match response.status() {
-                StatusCode::OK => {
-                    let response_body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
-                    let deserialized_response: serde_json::Value =
-                        serde_json::from_slice(&response_body).unwrap();
-
-                    let mut image_objects: Vec<ImageObject> = vec![];
-                    if let Some(images) = deserialized_response.get("images") {
-                        let image_vec = images.as_array().unwrap();
-                        info!(target: "stdout", "number of images: {}", image_vec.len());
-
-                        for image in image_vec {
-                            if let serde_json::Value::String(b64) = image {
-                                image_objects.push(ImageObject {
-                                    b64_json: Some(b64.clone()),
-                                    url: None,
-                                    prompt: Some(image_request.prompt.clone()),
-                                })
-                            }
+                            return Err(error::bad_request(err_msg));
                         }
+                    };
+                }
+
+                let image_request: Txt2ImgRequest = match serde_json::from_value(value) {
+                    Ok(image_request) => image_request,
+                    Err(e) => {
+                        let err_msg =
+                            format!("Fail to deserialize image create request: {msg}", msg = e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(error::bad_request(err_msg));
                     }
+                };
 
-                    let response_body = serde_json::to_string(&image_objects).unwrap();
-                    let response = Response::builder()
-                        .status(StatusCode::OK)
-                        .header("Content-Type", "application/json")
-                        .body(Body::from(response_body))
-                        .unwrap();
+                let body = serde_json::to_string(&image_request).unwrap();
 
-                    Ok(response)
-                }
-                _ => {
-                    warn!(target: "stdout", "status is not ok");
+                (body, image_request.prompt)
+            }
+            GenerationKind::Img2Img => {
+                info!(target: "stdout", "Prepare the image edit request.");
 
-                    Ok(response)
-                }
-            },
-            Err(e) => {
-                let err_msg = format!(
-                    "failed to forward the request to the downstream server: {}",
-                    e
-                );
+                let content_type = req
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                let edit_request = match ImageEditRequest::from_multipart(content_type, &body_bytes)
+                {
+                    Ok(edit_request) => edit_request,
+                    Err(e) => {
+                        let err_msg = format!("Fail to parse image edit request: {msg}", msg = e);
 
-                error!(target: "stdout", "{}", &err_msg);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(error::bad_request(err_msg));
+                    }
+                };
 
-                Ok(error::internal_server_error(&err_msg))
+                let body = edit_request.to_img2img_payload().to_string();
+                stream = edit_request.stream;
+                output = edit_request.output;
+
+                (body, edit_request.prompt)
             }
+        };
+
+        if output.response_format == ResponseFormat::Url && state.image_store.is_none() {
+            let err_msg = "`response_format` `url` requires an image store. Set `--image-store-dir` to enable it.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::bad_request(err_msg));
         }
-    } else {
-        let err_msg = format!(
-            "404 The requested service endpoint is not found: {}",
-            endpoint
-        );
 
-        error!(target: "stdout", "{}", &err_msg);
+        Ok(Self {
+            kind,
+            body: Bytes::from(body),
+            prompt,
+            model,
+            keeps_model,
+            stream,
+            output,
+        })
+    }
+}
+
+/// Reasons a generation fails
+#[derive(Debug)]
+pub(crate) enum GenerationError {
+    /// The request could not be dispatched to a downstream server
+    Server(ServerError),
+    /// The downstream server responded with an error status
+    Downstream {
+        status: StatusCode,
+        content_type: Option<HeaderValue>,
+        body: Bytes,
+    },
+    /// The downstream server responded with a body that is not a generation result
+    InvalidResponse(String),
+}
+impl GenerationError {
+    /// Builds the response sent to a client waiting for the generation.
+    fn into_response(self) -> Response<Body> {
+        match self {
+            GenerationError::Server(e @ (ServerError::QueueFull | ServerError::QueueTimeout)) => {
+                error::service_unavailable(e.to_string(), QUEUE_RETRY_AFTER)
+            }
+            GenerationError::Server(e @ ServerError::ModelNotServed(_)) => {
+                error::not_found(e.to_string(), "model_not_found")
+            }
+            GenerationError::Server(e) => error::internal_server_error(e.to_string()),
+            GenerationError::Downstream {
+                status,
+                content_type,
+                body,
+            } => {
+                warn!(target: "stdout", "status is not ok");
 
-        Ok(error::internal_server_error(&err_msg))
+                // hand the downstream error back to the client as is
+                let mut builder = Response::builder().status(status);
+                if let Some(content_type) = content_type {
+                    builder = builder.header(CONTENT_TYPE, content_type);
+                }
+                builder.body(Body::from(body)).unwrap()
+            }
+            GenerationError::InvalidResponse(msg) => error::internal_server_error(msg),
+        }
+    }
+}
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerationError::Server(e) => write!(f, "{}", e),
+            GenerationError::Downstream { status, body, .. } => write!(
+                f,
+                "the downstream server responded with {}: {}",
+                status,
+                String::from_utf8_lossy(body)
+            ),
+            GenerationError::InvalidResponse(msg) => write!(f, "{}", msg),
+        }
     }
 }
 
-pub(crate) async fn add_url_handler(
-    State(state): State<AppState>,
-    Path(url_type): Path<String>,
-    body: String,
-) -> Result<Response<Body>, StatusCode> {
-    info!(target: "stdout", "url_type: {}", url_type);
-    info!(target: "stdout", "body: {}", &body);
+/// Runs a generation on a downstream server and returns the generated images.
+///
+/// `on_dispatch` is called with the url of the downstream server the request is sent to.
+pub(crate) async fn run_generation(
+    state: &AppState,
+    task: &GenerationTask,
+    on_dispatch: &(dyn Fn(&Uri) + Send + Sync),
+) -> Result<Vec<GeneratedImage>, GenerationError> {
+    // Forward the request to the downstream server
+    let (downstream, mut response) = forward_request(
+        state,
+        task.kind.sdapi_endpoint(),
+        task.body.clone(),
+        task.model.as_deref(),
+        on_dispatch,
+    )
+    .await?;
 
-    let url_type = match url_type.as_str() {
-        "image" => UrlType::Image,
-        _ => return Err(StatusCode::BAD_REQUEST),
-    };
+    let status = response.status();
+    let response_body = to_bytes(response.body_mut()).await.map_err(|e| {
+        GenerationError::InvalidResponse(format!(
+            "failed to read the response of the downstream server: {}",
+            e
+        ))
+    })?;
 
-    let url: Uri = match body.parse() {
-        Ok(url) => url,
-        Err(_) => {
-            let err_msg = format!("invalid url: {}", &body);
+    if status != StatusCode::OK {
+        return Err(GenerationError::Downstream {
+            status,
+            content_type: response.headers().get(CONTENT_TYPE).cloned(),
+            body: response_body,
+        });
+    }
 
-            error!(target: "stdout", "{}", &err_msg);
+    // the server keeps the requested checkpoint loaded, unless it restores the previous one
+    if let Some(model) = task.model.as_ref().filter(|_| task.keeps_model) {
+        downstream.server().set_loaded_model(model);
+    }
 
-            return Ok(error::internal_server_error(&err_msg));
+    let deserialized_response: serde_json::Value =
+        serde_json::from_slice(&response_body).map_err(|e| {
+            GenerationError::InvalidResponse(format!(
+                "failed to parse the response of the downstream server: {}",
+                e
+            ))
+        })?;
+
+    let mut generated_images: Vec<GeneratedImage> = vec![];
+    if let Some(images) = deserialized_response
+        .get("images")
+        .and_then(|v| v.as_array())
+    {
+        info!(target: "stdout", "number of images: {}", images.len());
+
+        let infos = match task.output.include_info || state.generation_info {
+            true => images::parse_generation_info(&deserialized_response, images.len()),
+            false => vec![None; images.len()],
+        };
+        for (image, info) in images.iter().zip(infos) {
+            if let serde_json::Value::String(b64) = image {
+                generated_images.push(GeneratedImage {
+                    image: build_image_object(state, task, b64).await?,
+                    info,
+                });
+            }
         }
-    };
-    if let Err(e) = state.add_url(url_type, &url).await {
-        let err_msg = e.to_string();
+    }
 
-        info!(target: "stdout", "{}", &err_msg);
+    Ok(generated_images)
+}
 
-        return Ok(error::internal_server_error(&err_msg));
+/// Builds the `ImageObject` of an image generated by the downstream server. The image is
+/// converted to the requested format, its PNG metadata rewritten per the deployment policy,
+/// and stored if the client asked for a URL.
+async fn build_image_object(
+    state: &AppState,
+    task: &GenerationTask,
+    b64: &str,
+) -> Result<ImageObject, GenerationError> {
+    let output = &task.output;
+    if output.format.is_none()
+        && output.response_format == ResponseFormat::B64Json
+        && state.png_metadata.policy == MetadataPolicy::Keep
+    {
+        // hand the image over as is
+        return Ok(ImageObject {
+            b64_json: Some(b64.to_string()),
+            url: None,
+            prompt: Some(task.prompt.clone()),
+        });
     }
 
-    // create a response with status code 200. Content-Type is JSON
-    let json_body = serde_json::json!({
-        "message": "URL registered successfully",
-        "url": url.to_string()
+    let mut data = general_purpose::STANDARD.decode(b64).map_err(|e| {
+        GenerationError::InvalidResponse(format!("failed to decode the image: {}", e))
+    })?;
+    if let Some(format) = output.format {
+        data = output::convert_image(&data, format, output.compression)
+            .map_err(GenerationError::InvalidResponse)?;
+    }
+    data = state
+        .png_metadata
+        .apply(data)
+        .map_err(GenerationError::InvalidResponse)?;
+
+    let image_object = match (output.response_format, &state.image_store) {
+        (ResponseFormat::Url, Some(image_store)) => ImageObject {
+            b64_json: None,
+            url: Some(
+                image_store
+                    .save(&data)
+                    .await
+                    .map_err(GenerationError::Server)?,
+            ),
+            prompt: Some(task.prompt.clone()),
+        },
+        _ => ImageObject {
+            b64_json: Some(general_purpose::STANDARD.encode(&data)),
+            url: None,
+            prompt: Some(task.prompt.clone()),
+        },
+    };
+
+    Ok(image_object)
+}
+
+/// Whether the client asked for a job with `?async=true`.
+fn is_async(uri: &Uri) -> bool {
+    uri.query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|pair| matches!(pair, "async=true" | "async=1"))
+    })
+}
+
+/// Whether the client asked for the legacy bare array of images with the
+/// `x-sd-proxy-legacy-response` header.
+fn wants_legacy_response(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(LEGACY_RESPONSE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| matches!(value.trim(), "true" | "1"))
+}
+
+/// API key of the client, which owns the jobs it submits.
+fn owner(req: &Request<Body>) -> Option<String> {
+    req.extensions()
+        .get::<ApiKeyIdentity>()
+        .map(|identity| identity.key.clone())
+}
+
+/// Takes the rate limit permit of a request, so that a job can hold it until it is done.
+fn take_permit(req: &mut Request<Body>) -> Option<RatePermit> {
+    req.extensions_mut().remove::<RatePermit>()
+}
+
+/// Starts a generation in the background and responds with the job to poll.
+fn submit_job(
+    state: AppState,
+    task: GenerationTask,
+    owner: Option<String>,
+    permit: Option<RatePermit>,
+) -> Response<Body> {
+    let id = start_job(&state, task, owner.clone(), permit);
+    let job = state.jobs.get(&id, owner.as_deref()).unwrap_or_default();
+
+    // create a response with status code 202. Content-Type is JSON
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(job.to_string()))
+        .unwrap()
+}
+
+/// Creates a job and runs its generation in the background. Returns the id of the job.
+///
+/// The rate limit permit of the request is held until the generation is done or cancelled.
+fn start_job(
+    state: &AppState,
+    task: GenerationTask,
+    owner: Option<String>,
+    permit: Option<RatePermit>,
+) -> String {
+    let id = state.jobs.create(owner);
+    info!(target: "stdout", "submitted job {}", &id);
+
+    let state = state.clone();
+    let jobs = state.jobs.clone();
+    let job_id = id.clone();
+    let task = tokio::spawn(async move {
+        let jobs = state.jobs.clone();
+        let result = run_generation(&state, &task, &|url| jobs.set_running(&job_id, url)).await;
+        if let Err(e) = &result {
+            warn!(target: "stdout", "job {} failed: {}", &job_id, e);
+        }
+        jobs.finish(&job_id, result.map_err(|e| e.to_string()));
+        drop(permit);
     });
+    jobs.attach(&id, task.abort_handle());
+
+    id
+}
+
+/// Serves an image kept in the image store.
+pub(crate) async fn image_file_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response<Body>, StatusCode> {
+    let image = match &state.image_store {
+        Some(image_store) => image_store.load(&id).await,
+        None => None,
+    };
+    let image = match image {
+        Some(image) => image,
+        None => {
+            return Ok(error::not_found(
+                format!("The image {} does not exist or has expired.", id),
+                "image_not_found",
+            ))
+        }
+    };
 
+    // create a response with status code 200. Content-Type is the type of the image
     let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(Body::from(json_body.to_string()))
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", image.content_type)
+        .header("Cache-Control", "private, max-age=3600")
+        .body(Body::from(image.data))
         .unwrap();
 
     Ok(response)
 }
 
-pub(crate) async fn remove_url_handler(
+/// Lists the checkpoints served by the registered downstream servers.
+pub(crate) async fn list_models_handler(
     State(state): State<AppState>,
-    Path(url_type): Path<String>,
-    body: String,
 ) -> Result<Response<Body>, StatusCode> {
-    info!(target: "stdout", "In remove_url_handler");
-
-    let url_type = match url_type.as_str() {
-        "image" => UrlType::Image,
-        _ => {
-            let err_msg = format!("invalid url type: {}", url_type);
-            error!(target: "stdout", "{}", &err_msg);
-            return Ok(error::internal_server_error(&err_msg));
-        }
-    };
+    let models = state.models.get(&state).await;
 
-    let url: Uri = match body.parse() {
-        Ok(url) => url,
-        Err(_) => {
-            let err_msg = format!("invalid url: {}", &body);
+    // create a response with status code 200. Content-Type is JSON
+    let response = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(models.as_ref()).unwrap()))
+        .unwrap();
 
-            error!(target: "stdout", "{}", &err_msg);
+    Ok(response)
+}
 
-            return Ok(error::internal_server_error(&err_msg));
-        }
+/// Submits a txt2img job, same as `POST /v1/images/generations?async=true`.
+pub(crate) async fn create_job_handler(
+    State(state): State<AppState>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let task = match GenerationTask::from_request(&state, GenerationKind::Txt2Img, &mut req).await {
+        Ok(task) => task,
+        Err(response) => return Ok(response),
     };
-    if let Err(e) = state.remove_url(url_type, &url).await {
-        let err_msg = e.to_string();
 
-        error!(target: "stdout", "{}", &err_msg);
-
-        return Ok(error::internal_server_error(&err_msg));
-    }
+    let permit = take_permit(&mut req);
+    Ok(submit_job(state, task, owner(&req), permit))
+}
 
-    info!(target: "stdout", "unregistered {}", url);
+/// Returns the status of a job, and its result once it has finished.
+pub(crate) async fn get_job_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let job = match state.jobs.get(&id, owner(&req).as_deref()) {
+        Some(job) => job,
+        None => {
+            return Ok(error::not_found(
+                format!("The job {} does not exist or has expired.", id),
+                "job_not_found",
+            ))
+        }
+    };
 
     // create a response with status code 200. Content-Type is JSON
-    let json_body = serde_json::json!({
-        "message": "URL unregistered successfully",
-        "url": url.to_string()
-    });
-
     let response = Response::builder()
-        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
         .header("Content-Type", "application/json")
-        .body(Body::from(json_body.to_string()))
+        .body(Body::from(job.to_string()))
         .unwrap();
 
     Ok(response)
 }
 
-pub(crate) async fn list_downstream_servers_handler(
+/// Cancels a job and interrupts the downstream server running it. The response tells in
+/// `interrupted` whether the server was interrupted.
+pub(crate) async fn cancel_job_handler(
     State(state): State<AppState>,
+    Path(id): Path<String>,
+    req: Request<Body>,
 ) -> Result<Response<Body>, StatusCode> {
-    let servers = state.list_downstream_servers().await;
+    let cancellation = match state.jobs.cancel(&id, owner(&req).as_deref()) {
+        Some(cancellation) => cancellation,
+        None => {
+            return Ok(error::not_found(
+                format!("The job {} does not exist or has expired.", id),
+                "job_not_found",
+            ))
+        }
+    };
 
-    // create a response with status code 200. Content-Type is JSON
-    let json_body = serde_json::json!({
-        "image": servers.get("image").unwrap(),
-    });
+    // the aborted attempt sends the interrupt on its way out, see `InterruptOnDrop`
+    let interrupted = match &cancellation.aborted_on {
+        Some(url) => {
+            let server = state.image_urls.read().await.find(url).await;
+            server.is_some_and(|server| server.is_interruptible())
+        }
+        None => false,
+    };
+    let mut job = cancellation.job;
+    job["interrupted"] = serde_json::Value::Bool(interrupted);
 
+    // create a response with status code 200. Content-Type is JSON
     let response = Response::builder()
-        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
         .header("Content-Type", "application/json")
-        .body(Body::from(json_body.to_string()))
+        .body(Body::from(job.to_string()))
         .unwrap();
 
     Ok(response)
 }
 
-// convert an image file to a base64 string
-fn _image_to_base64(image_path: impl AsRef<std::path::Path>) -> std::io::Result<String> {
-    // Open the file
-    let mut image_file = File::open(image_path)?;
+/// Streams the progress of a job as Server-Sent Events until it is done.
+pub(crate) async fn job_events_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    req: Request<Body>,
+) -> Result<Response<Body>, StatusCode> {
+    let owner = owner(&req);
+    if state.jobs.get(&id, owner.as_deref()).is_none() {
+        return Ok(error::not_found(
+            format!("The job {} does not exist or has expired.", id),
+            "job_not_found",
+        ));
+    }
 
-    // Read the file into a byte array
-    let mut buffer = Vec::new();
-    image_file.read_to_end(&mut buffer)?;
+    Ok(progress::job_events_response(state, id, owner, false))
+}
 
-    Ok(general_purpose::STANDARD.encode(&buffer))
+/// Forwards a request to a downstream server, failing over to another server on connect
+/// errors, timeouts and 502/503 responses.
+///
+/// Every attempt goes to a server that has not been tried yet, and the attempts are bounded
+/// by both `RetryConfig::max_attempts` and `RetryConfig::budget`, which includes the time
+/// spent in the queue. The returned guard keeps the in-flight slot of the server that produced
+/// the response.
+///
+/// If every attempt fails, the last 502/503 response of a downstream server is returned as
+/// `GenerationError::Downstream`, even if later attempts failed to connect.
+async fn forward_request(
+    state: &AppState,
+    sdapi_endpoint: &str,
+    body: Bytes,
+    model: Option<&str>,
+    on_dispatch: &(dyn Fn(&Uri) + Send + Sync),
+) -> Result<(ConnectionGuard, Response<Body>), GenerationError> {
+    let retry = &state.retry;
+    let start = Instant::now();
+    let mut tried: Vec<Uri> = vec![];
+    let mut last_error = ServerError::NotFoundServer;
+    let mut last_response = None;
+
+    for attempt in 1..=retry.max_attempts {
+        if start.elapsed() >= retry.budget {
+            warn!(target: "stdout", "retry budget of {:?} exhausted", retry.budget);
+            break;
+        }
+
+        let route = RouteContext {
+            excluded: &tried,
+            model,
+        };
+        let downstream = match state.image_urls.read().await.next(&route).await {
+            Ok(downstream) => downstream,
+            Err(e) => {
+                if tried.is_empty() {
+                    return Err(GenerationError::Server(e));
+                }
+                warn!(target: "stdout", "no other server to fail over to: {}", e);
+                break;
+            }
+        };
+        tried.push(downstream.url().clone());
+
+        // the request may have waited in the queue
+        let remaining = match retry.budget.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                warn!(target: "stdout", "retry budget of {:?} exhausted in the queue", retry.budget);
+                break;
+            }
+        };
+
+        let server_socket_addr = downstream.url().to_string();
+        let downstream_uri: Uri = format!(
+            "{}/{}",
+            server_socket_addr.trim_end_matches('/'),
+            sdapi_endpoint
+        )
+        .parse()
+        .unwrap();
+        info!(target: "stdout", "attempt {}/{}: dispatch the request to {}", attempt, retry.max_attempts, downstream_uri);
+        on_dispatch(downstream.url());
+
+        // create a request to the downstream server
+        let downstream_request = Request::builder()
+            .method("POST")
+            .uri(downstream_uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.clone()))
+            .unwrap();
+
+        let timeout = downstream
+            .server()
+            .request_timeout
+            .unwrap_or(retry.request_timeout)
+            .min(remaining);
+        // interrupt the generation if the caller goes away or the attempt times out
+        let interrupt = InterruptOnDrop::new(state.client.clone(), downstream.server());
+        let attempt_start = Instant::now();
+        let result = tokio::time::timeout(timeout, state.client.request(downstream_request)).await;
+        if result.is_ok() {
+            interrupt.disarm();
+        }
+
+        match result {
+            Ok(Ok(mut response)) => match response.status() {
+                status @ (StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE) => {
+                    let err_msg = format!(
+                        "the downstream server {} responded with {}",
+                        downstream.url(),
+                        status
+                    );
+                    warn!(target: "stdout", "attempt {} failed: {}", attempt, &err_msg);
+
+                    // keep the response without the slot of the server, which the next
+                    // attempts may need
+                    let body = to_bytes(response.body_mut()).await.unwrap_or_default();
+                    last_response = Some(GenerationError::Downstream {
+                        status,
+                        content_type: response.headers().get(CONTENT_TYPE).cloned(),
+                        body,
+                    });
+                    last_error = ServerError::Operation(err_msg);
+                }
+                _ => {
+                    // SD WebUI responds once the images are generated
+                    downstream.server().record_latency(attempt_start.elapsed());
+                    return Ok((downstream, response));
+                }
+            },
+            Ok(Err(e)) if e.is_connect() => {
+                let err_msg = format!(
+                    "failed to connect to the downstream server {}: {}",
+                    downstream.url(),
+                    e
+                );
+                warn!(target: "stdout", "attempt {} failed: {}", attempt, &err_msg);
+
+                last_error = ServerError::Operation(err_msg);
+            }
+            Ok(Err(e)) => {
+                return Err(GenerationError::Server(ServerError::Operation(format!(
+                    "failed to forward the request to the downstream server {}: {}",
+                    downstream.url(),
+                    e
+                ))));
+            }
+            Err(_) => {
+                let err_msg = format!(
+                    "the downstream server {} did not respond within {:?}",
+                    downstream.url(),
+                    timeout
+                );
+                warn!(target: "stdout", "attempt {} failed: {}", attempt, &err_msg);
+
+                last_error = ServerError::Operation(err_msg);
+            }
+        }
+    }
+
+    // hand the last 502/503 response back to the client as is
+    Err(last_response.unwrap_or(GenerationError::Server(last_error)))
+}
+
+/// Interrupts the generation running on a downstream server when dropped, unless it is
+/// disarmed once the downstream server has responded.
+///
+/// The in-flight request is dropped when the client disconnects, when its job is cancelled or
+/// when the attempt times out, and SD WebUI would otherwise keep generating for nobody.
+///
+/// SD WebUI stops whatever it is generating, so only the servers running a single request at a
+/// time are interrupted. On the other servers, the generation may belong to another request.
+struct InterruptOnDrop {
+    client: SharedClient,
+    server: Option<Uri>,
+}
+impl InterruptOnDrop {
+    fn new(client: SharedClient, server: &Server) -> Self {
+        Self {
+            client,
+            server: server.is_interruptible().then(|| server.url.clone()),
+        }
+    }
+
+    fn disarm(mut self) {
+        self.server = None;
+    }
+}
+impl Drop for InterruptOnDrop {
+    fn drop(&mut self) {
+        let server = match self.server.take() {
+            Some(server) => server,
+            None => return,
+        };
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+
+        let client = self.client.clone();
+        runtime.spawn(async move { interrupt(&client, &server).await });
+    }
+}
+
+/// Asks a downstream server to stop its current generation.
+async fn interrupt(client: &SharedClient, server: &Uri) {
+    let uri: Uri = match format!(
+        "{}/{}",
+        server.to_string().trim_end_matches('/'),
+        INTERRUPT_ENDPOINT
+    )
+    .parse()
+    {
+        Ok(uri) => uri,
+        Err(_) => return,
+    };
+    info!(target: "stdout", "interrupt the generation on {}", server);
+
+    let request = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+    match tokio::time::timeout(INTERRUPT_TIMEOUT, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => {}
+        Ok(Ok(response)) => {
+            warn!(target: "stdout", "failed to interrupt {}: {}", server, response.status())
+        }
+        Ok(Err(e)) => warn!(target: "stdout", "failed to interrupt {}: {}", server, e),
+        Err(_) => warn!(target: "stdout", "failed to interrupt {}: timed out", server),
+    }
+}
+
+/// Body of `/admin/register/{url_type}` in JSON
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegisterServerRequest {
+    url: String,
+    /// Checkpoints served by the server, in addition to the discovered ones
+    #[serde(default)]
+    models: Vec<String>,
+    /// Share of the traffic of the server under the weighted routing policies
+    weight: Option<u32>,
+}
+
+/// Query of `/admin/register/{url_type}`
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegisterServerQuery {
+    weight: Option<u32>,
+}
+
+/// Body of `/admin/policy/{url_type}`
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetPolicyRequest {
+    policy: PolicyKind,
+}
+
+/// Body of `/admin/weight/{url_type}`
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetWeightRequest {
+    url: String,
+    weight: u32,
+}
+
+pub(crate) async fn add_url_handler(
+    State(state): State<AppState>,
+    Path(url_type): Path<String>,
+    Query(query): Query<RegisterServerQuery>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    info!(target: "stdout", "url_type: {}", url_type);
+    info!(target: "stdout", "body: {}", &body);
+
+    let url_type = match url_type.as_str() {
+        "image" => UrlType::Image,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    // either a bare url, or a JSON object declaring the checkpoints of the server
+    let registration = match body.trim_start().starts_with('{') {
+        true => match serde_json::from_str::<RegisterServerRequest>(&body) {
+            Ok(registration) => registration,
+            Err(e) => {
+                let err_msg = format!("invalid registration request: {}", e);
+
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Ok(error::bad_request(&err_msg));
+            }
+        },
+        false => RegisterServerRequest {
+            url: body.trim().to_string(),
+            models: vec![],
+            weight: None,
+        },
+    };
+
+    let weight = registration.weight.or(query.weight);
+    if weight == Some(0) {
+        let err_msg = "`weight` must be at least 1";
+
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Ok(error::bad_request(err_msg));
+    }
+
+    let url: Uri = match registration.url.parse() {
+        Ok(url) => url,
+        Err(_) => {
+            let err_msg = format!("invalid url: {}", &registration.url);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::internal_server_error(&err_msg));
+        }
+    };
+    let server = Server::new(url.clone())
+        .with_models(registration.models)
+        .with_weight(weight);
+    if let Err(e) = state.add_url(url_type, server).await {
+        let err_msg = e.to_string();
+
+        info!(target: "stdout", "{}", &err_msg);
+
+        return Ok(error::internal_server_error(&err_msg));
+    }
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "URL registered successfully",
+        "url": url.to_string()
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) async fn remove_url_handler(
+    State(state): State<AppState>,
+    Path(url_type): Path<String>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    info!(target: "stdout", "In remove_url_handler");
+
+    let url_type = match url_type.as_str() {
+        "image" => UrlType::Image,
+        _ => {
+            let err_msg = format!("invalid url type: {}", url_type);
+            error!(target: "stdout", "{}", &err_msg);
+            return Ok(error::internal_server_error(&err_msg));
+        }
+    };
+
+    let url: Uri = match body.parse() {
+        Ok(url) => url,
+        Err(_) => {
+            let err_msg = format!("invalid url: {}", &body);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::internal_server_error(&err_msg));
+        }
+    };
+    if let Err(e) = state.remove_url(url_type, &url).await {
+        let err_msg = e.to_string();
+
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Ok(error::internal_server_error(&err_msg));
+    }
+
+    info!(target: "stdout", "unregistered {}", url);
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "URL unregistered successfully",
+        "url": url.to_string()
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Changes the weight of a registered downstream server without registering it again.
+pub(crate) async fn set_weight_handler(
+    State(state): State<AppState>,
+    Path(url_type): Path<String>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    let url_type = match url_type.as_str() {
+        "image" => UrlType::Image,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let request: SetWeightRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("invalid request body: {}", e);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+    if request.weight == 0 {
+        let err_msg = "`weight` must be at least 1";
+
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Ok(error::bad_request(err_msg));
+    }
+
+    let url: Uri = match request.url.parse() {
+        Ok(url) => url,
+        Err(_) => {
+            let err_msg = format!("invalid url: {}", &request.url);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+    if let Err(e) = state.set_weight(url_type, &url, request.weight).await {
+        let err_msg = e.to_string();
+
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Ok(match e {
+            ServerError::NotFoundServer => {
+                error::not_found(format!("{} is not registered", url), "server_not_found")
+            }
+            _ => error::internal_server_error(&err_msg),
+        });
+    }
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "Weight updated successfully",
+        "url": url.to_string(),
+        "weight": request.weight,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+/// Switches the routing policy of a type of downstream servers at runtime.
+pub(crate) async fn set_policy_handler(
+    State(state): State<AppState>,
+    Path(url_type): Path<String>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    let url_type = match url_type.as_str() {
+        "image" => UrlType::Image,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let request: SetPolicyRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("invalid request body: {}", e);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+
+    let previous = state.set_policy(url_type, request.policy).await;
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "Routing policy updated successfully",
+        "policy": request.policy,
+        "previous": previous,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) async fn list_downstream_servers_handler(
+    State(state): State<AppState>,
+) -> Result<Response<Body>, StatusCode> {
+    let servers = state.list_downstream_servers().await;
+
+    let queues = state.queue_reports().await;
+
+    let policies = state.policies().await;
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "image": servers.get("image").unwrap(),
+        "queue": queues,
+        "policy": policies,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AddApiKeyRequest {
+    /// Name of the customer owning the key
+    name: String,
+    /// The key to add. A random key is generated if it is absent.
+    key: Option<String>,
+    /// Rate limit of the key, overriding the default per-key limit
+    rate_limit: Option<RateLimit>,
+}
+
+/// Revokes either a single key, or every key of a customer
+#[derive(Debug, Deserialize)]
+pub(crate) struct RevokeApiKeyRequest {
+    key: Option<String>,
+    name: Option<String>,
+}
+
+pub(crate) async fn list_api_keys_handler(
+    State(state): State<AppState>,
+) -> Result<Response<Body>, StatusCode> {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(api_keys_disabled()),
+    };
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "keys": api_keys.list().await,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) async fn add_api_key_handler(
+    State(state): State<AppState>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(api_keys_disabled()),
+    };
+
+    let request: AddApiKeyRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("invalid request body: {}", e);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+
+    let api_key = match api_keys
+        .add(&request.name, request.key, request.rate_limit)
+        .await
+    {
+        Ok(api_key) => api_key,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "API key added successfully",
+        "key": api_key.key,
+        "name": api_key.name,
+        "created": api_key.created,
+        "rate_limit": api_key.rate_limit,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) async fn revoke_api_key_handler(
+    State(state): State<AppState>,
+    body: String,
+) -> Result<Response<Body>, StatusCode> {
+    let api_keys = match &state.api_keys {
+        Some(api_keys) => api_keys,
+        None => return Ok(api_keys_disabled()),
+    };
+
+    let request: RevokeApiKeyRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let err_msg = format!("invalid request body: {}", e);
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+
+    let result = match (&request.key, &request.name) {
+        (Some(key), None) => api_keys.revoke(key).await,
+        (None, Some(name)) => api_keys.revoke_by_name(name).await,
+        _ => Err(ServerError::Operation(
+            "exactly one of `key` and `name` must be given".to_string(),
+        )),
+    };
+    let revoked = match result {
+        Ok(revoked) => revoked,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Ok(error::bad_request(&err_msg));
+        }
+    };
+
+    // the revoked keys no longer need their rate limit buckets
+    for key in &revoked {
+        state.rate_limiter.forget(&key.key);
+    }
+    let revoked: Vec<ApiKeySummary> = revoked.iter().map(|key| key.summary()).collect();
+
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = serde_json::json!({
+        "message": "API keys revoked successfully",
+        "revoked": revoked,
+    });
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) async fn rate_limits_handler(
+    State(state): State<AppState>,
+) -> Result<Response<Body>, StatusCode> {
+    // create a response with status code 200. Content-Type is JSON
+    let json_body = state.rate_limiter.report();
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+fn api_keys_disabled() -> Response<Body> {
+    error::bad_request("API key authentication is disabled. Start the proxy with `--api-keys-file` to manage API keys.")
+}
+
+// convert an image file to a base64 string
+fn _image_to_base64(image_path: impl AsRef<std::path::Path>) -> std::io::Result<String> {
+    // Open the file
+    let mut image_file = File::open(image_path)?;
+
+    // Read the file into a byte array
+    let mut buffer = Vec::new();
+    image_file.read_to_end(&mut buffer)?;
+
+    Ok(general_purpose::STANDARD.encode(&buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rate_limit::RateLimiter,
+        storage::tests::MemoryImageStore,
+        tests::{add_server, app_state, mock_backend},
+        RetryConfig,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn connections(server: &Server) -> usize {
+        server.connections.load(Ordering::Relaxed)
+    }
+
+    /// Starts a downstream server answering after `delay`, and counting the requests it gets.
+    async fn slow_backend(delay: Duration, hits: Arc<AtomicUsize>) -> Uri {
+        mock_backend(move |req| {
+            let hits = hits.clone();
+            async move {
+                if req.uri().path().ends_with("txt2img") {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                }
+                tokio::time::sleep(delay).await;
+                Response::new(Body::from(r#"{"images":[]}"#))
+            }
+        })
+        .await
+    }
+
+    /// Returns the url of a port nothing listens on.
+    fn refused_backend() -> Uri {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap()
+    }
+
+    /// Starts a downstream server closing every connection without responding.
+    fn closing_backend() -> Uri {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+
+        format!("http://{}", addr).parse().unwrap()
+    }
+
+    async fn forward(
+        state: &AppState,
+    ) -> Result<(ConnectionGuard, Response<Body>), GenerationError> {
+        forward_request(
+            state,
+            "sdapi/v1/txt2img",
+            Bytes::from_static(b"{}"),
+            None,
+            &|_| {},
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn least_connections_spreads_concurrent_slow_requests() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let mut servers = vec![];
+        let mut hits = vec![];
+        for _ in 0..3 {
+            let count = Arc::new(AtomicUsize::new(0));
+            let url = slow_backend(Duration::from_millis(200), count.clone()).await;
+            servers.push(add_server(&state, Server::new(url)).await);
+            hits.push(count);
+        }
+
+        let requests = (0..6).map(|_| forward(&state));
+        let results = futures_util::future::join_all(requests).await;
+
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(servers.iter().map(|s| connections(s)).sum::<usize>(), 6);
+        for count in &hits {
+            assert_eq!(count.load(Ordering::Relaxed), 2);
+        }
+
+        drop(results);
+        assert!(servers.iter().all(|s| connections(s) == 0));
+    }
+
+    #[tokio::test]
+    async fn response_keeps_the_slot_until_dropped() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let url = slow_backend(Duration::ZERO, Arc::new(AtomicUsize::new(0))).await;
+        let server = add_server(&state, Server::new(url)).await;
+
+        let (guard, response) = forward(&state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(connections(&server), 1);
+
+        drop(guard);
+        assert_eq!(connections(&server), 0);
+    }
+
+    #[tokio::test]
+    async fn error_responses_fail_over_and_release_the_slots() {
+        let unavailable = mock_backend(|_| async {
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from("overloaded"))
+                .unwrap()
+        })
+        .await;
+
+        // the 503 is handed back to the client whether the connect error comes before or after
+        for unavailable_first in [false, true] {
+            let state = app_state(PolicyKind::RoundRobin, Some(1));
+            let mut servers = vec![];
+            if unavailable_first {
+                servers.push(add_server(&state, Server::new(unavailable.clone())).await);
+            }
+            servers.push(add_server(&state, Server::new(refused_backend())).await);
+            if !unavailable_first {
+                servers.push(add_server(&state, Server::new(unavailable.clone())).await);
+            }
+
+            match forward(&state).await {
+                Err(GenerationError::Downstream {
+                    status,
+                    content_type,
+                    body,
+                }) => {
+                    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+                    assert_eq!(content_type.unwrap(), "text/plain");
+                    assert_eq!(body, "overloaded");
+                }
+                other => panic!("unexpected result: {:?}", other.map(|(_, r)| r)),
+            }
+            assert!(servers.iter().all(|s| connections(s) == 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_give_their_slot_back() {
+        // a single server of concurrency 1, busy until its 503 is read
+        let state = app_state(PolicyKind::LeastConnections, Some(1));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let url = mock_backend(move |_| {
+            let hits = counter.clone();
+            async move {
+                let status = match hits.fetch_add(1, Ordering::Relaxed) {
+                    0 => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::OK,
+                };
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        })
+        .await;
+        let server = add_server(&state, Server::new(url)).await;
+        let other = add_server(&state, Server::new(refused_backend())).await;
+
+        // the 503 and the connect error are followed by no other server to try
+        assert!(forward(&state).await.is_err());
+        assert_eq!((connections(&server), connections(&other)), (0, 0));
+
+        // the slot is free for the next request
+        let (guard, response) = forward(&state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(guard.url(), &server.url);
+    }
+
+    #[tokio::test]
+    async fn queue_wait_counts_against_the_budget() {
+        let mut state = app_state(PolicyKind::LeastConnections, Some(1));
+        state.retry = Arc::new(RetryConfig {
+            max_attempts: 3,
+            request_timeout: Duration::from_secs(5),
+            budget: Duration::from_millis(400),
+        });
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = slow_backend(Duration::from_secs(10), hits.clone()).await;
+        let server = add_server(&state, Server::new(url)).await;
+
+        // the slot is taken for 300ms, leaving 100ms of the budget to the request
+        let busy = state
+            .image_urls
+            .read()
+            .await
+            .next(&RouteContext::default())
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let (result, _) = tokio::join!(forward(&state), async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            drop(busy);
+        });
+        assert!(matches!(
+            result,
+            Err(GenerationError::Server(ServerError::Operation(_)))
+        ));
+        assert!(
+            start.elapsed() < Duration::from_millis(550),
+            "{:?}",
+            start.elapsed()
+        );
+        assert_eq!(connections(&server), 0);
+    }
+
+    #[tokio::test]
+    async fn connect_errors_release_the_slots() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let servers = [
+            add_server(&state, Server::new(refused_backend())).await,
+            add_server(&state, Server::new(refused_backend())).await,
+        ];
+
+        assert!(matches!(
+            forward(&state).await,
+            Err(GenerationError::Server(ServerError::Operation(_)))
+        ));
+        assert!(servers.iter().all(|s| connections(s) == 0));
+    }
+
+    #[tokio::test]
+    async fn timeouts_release_the_slots() {
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.retry = Arc::new(RetryConfig {
+            max_attempts: 2,
+            request_timeout: Duration::from_millis(100),
+            budget: Duration::from_secs(5),
+        });
+        let hits = Arc::new(AtomicUsize::new(0));
+        let servers = [
+            add_server(
+                &state,
+                Server::new(slow_backend(Duration::from_secs(10), hits.clone()).await),
+            )
+            .await,
+            add_server(
+                &state,
+                Server::new(slow_backend(Duration::from_secs(10), hits.clone()).await),
+            )
+            .await,
+        ];
+
+        assert!(matches!(
+            forward(&state).await,
+            Err(GenerationError::Server(ServerError::Operation(_)))
+        ));
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+        assert!(servers.iter().all(|s| connections(s) == 0));
+    }
+
+    #[tokio::test]
+    async fn early_returns_release_the_slots() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+
+        // no server at all
+        assert!(matches!(
+            forward(&state).await,
+            Err(GenerationError::Server(ServerError::NotFoundServer))
+        ));
+
+        // the connection is closed without a response, which is not retried
+        let server = add_server(&state, Server::new(closing_backend())).await;
+        assert!(matches!(
+            forward(&state).await,
+            Err(GenerationError::Server(ServerError::Operation(_)))
+        ));
+        assert_eq!(connections(&server), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_callers_release_the_slots() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let url = slow_backend(Duration::from_secs(10), Arc::new(AtomicUsize::new(0))).await;
+        let server = add_server(&state, Server::new(url)).await;
+
+        // the client goes away while the server is generating
+        let result = tokio::time::timeout(Duration::from_millis(100), forward(&state)).await;
+        assert!(result.is_err());
+        assert_eq!(connections(&server), 0);
+    }
+
+    /// Builds a txt2img request admitted by the rate limiter of `state`.
+    fn generation_request(state: &AppState, uri: &str, body: &str) -> Request<Body> {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        req.extensions_mut()
+            .insert(state.rate_limiter.acquire(None).unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn async_jobs_hold_the_rate_limit_permit() {
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.rate_limiter = Arc::new(RateLimiter::new(
+            RateLimit {
+                requests_per_minute: None,
+                max_concurrent: Some(1),
+            },
+            RateLimit::default(),
+        ));
+        let url = slow_backend(Duration::from_millis(200), Arc::new(AtomicUsize::new(0))).await;
+        add_server(&state, Server::new(url)).await;
+
+        let req = generation_request(
+            &state,
+            "/v1/images/generations?async=true",
+            r#"{"prompt": "a cat"}"#,
+        );
+        let response = proxy_request(state.clone(), req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // the job is running, and keeps the only slot
+        assert!(state.rate_limiter.acquire(None).is_err());
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(state.rate_limiter.acquire(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn streamed_generations_hold_the_rate_limit_permit() {
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.rate_limiter = Arc::new(RateLimiter::new(
+            RateLimit {
+                requests_per_minute: None,
+                max_concurrent: Some(1),
+            },
+            RateLimit::default(),
+        ));
+        let url = slow_backend(Duration::from_millis(200), Arc::new(AtomicUsize::new(0))).await;
+        add_server(&state, Server::new(url)).await;
+
+        let req = generation_request(
+            &state,
+            "/v1/images/generations",
+            r#"{"prompt": "a cat", "stream": true}"#,
+        );
+        let response = proxy_request(state.clone(), req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the events are streamed while the generation keeps the only slot
+        assert!(state.rate_limiter.acquire(None).is_err());
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(state.rate_limiter.acquire(None).is_ok());
+    }
+
+    /// Starts a downstream server never finishing its generations, and counting the interrupts
+    /// it gets.
+    async fn hanging_backend(interrupts: Arc<AtomicUsize>) -> Uri {
+        mock_backend(move |req| {
+            let interrupts = interrupts.clone();
+            async move {
+                if req.uri().path().ends_with(INTERRUPT_ENDPOINT) {
+                    interrupts.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Response::new(Body::empty())
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn only_exclusive_servers_are_interrupted() {
+        for (max_concurrency, expected) in [(Some(1), 1), (Some(2), 0), (None, 0)] {
+            let state = app_state(PolicyKind::LeastConnections, max_concurrency);
+            let interrupts = Arc::new(AtomicUsize::new(0));
+            let url = hanging_backend(interrupts.clone()).await;
+            add_server(&state, Server::new(url)).await;
+
+            // the client goes away while the server is generating
+            let result = tokio::time::timeout(Duration::from_millis(100), forward(&state)).await;
+            assert!(result.is_err());
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(
+                interrupts.load(Ordering::Relaxed),
+                expected,
+                "max_concurrency: {:?}",
+                max_concurrency
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cancellations_report_the_interrupt() {
+        for (max_concurrency, expected) in [(Some(1), 1), (None, 0)] {
+            let state = app_state(PolicyKind::LeastConnections, max_concurrency);
+            let interrupts = Arc::new(AtomicUsize::new(0));
+            let url = hanging_backend(interrupts.clone()).await;
+            add_server(&state, Server::new(url)).await;
+
+            let req = generation_request(&state, "/v1/jobs", r#"{"prompt": "a cat"}"#);
+            let response = create_job_handler(State(state.clone()), req).await.unwrap();
+            let body = to_bytes(response.into_body()).await.unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let id = job["id"].as_str().unwrap().to_string();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let req = Request::delete(format!("/v1/jobs/{}", id))
+                .body(Body::empty())
+                .unwrap();
+            let response = cancel_job_handler(State(state.clone()), Path(id), req)
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body()).await.unwrap();
+            let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(job["status"], "cancelled");
+            assert_eq!(job["interrupted"], expected == 1);
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert_eq!(interrupts.load(Ordering::Relaxed), expected);
+        }
+    }
+
+    /// Starts a downstream server generating a single image.
+    async fn image_backend() -> Uri {
+        mock_backend(|_| async { Response::new(Body::from(r#"{"images": ["aGVsbG8="]}"#)) }).await
+    }
+
+    async fn generate(state: &AppState, legacy_header: Option<&str>) -> serde_json::Value {
+        let mut req = generation_request(state, "/v1/images/generations", r#"{"prompt": "a cat"}"#);
+        if let Some(value) = legacy_header {
+            req.headers_mut()
+                .insert(LEGACY_RESPONSE_HEADER, value.parse().unwrap());
+        }
+
+        let response = proxy_request(state.clone(), req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn images_are_wrapped_in_the_openai_envelope() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        add_server(&state, Server::new(image_backend().await)).await;
+
+        let response = generate(&state, None).await;
+        assert!(response["created"].is_u64());
+        assert_eq!(
+            response["data"],
+            serde_json::json!([{"b64_json": "aGVsbG8=", "prompt": "a cat"}])
+        );
+
+        // same shape as the response type of the `endpoints` crate
+        let expected = endpoints::images::ListImagesResponse {
+            created: response["created"].as_u64().unwrap(),
+            data: vec![ImageObject {
+                b64_json: Some("aGVsbG8=".to_string()),
+                url: None,
+                prompt: Some("a cat".to_string()),
+            }],
+        };
+        assert_eq!(response, serde_json::to_value(expected).unwrap());
+
+        let response = generate(&state, Some("false")).await;
+        assert!(response["data"].is_array());
+    }
+
+    #[tokio::test]
+    async fn legacy_responses_are_bare_arrays() {
+        let expected = serde_json::json!([{"b64_json": "aGVsbG8=", "prompt": "a cat"}]);
+
+        // asked for by the client
+        let state = app_state(PolicyKind::LeastConnections, None);
+        add_server(&state, Server::new(image_backend().await)).await;
+        assert_eq!(generate(&state, Some("true")).await, expected);
+        assert_eq!(generate(&state, Some("1")).await, expected);
+
+        // set for the whole deployment with `--legacy-response`
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.legacy_response = true;
+        add_server(&state, Server::new(image_backend().await)).await;
+        assert_eq!(generate(&state, None).await, expected);
+    }
+
+    #[tokio::test]
+    async fn only_kept_checkpoints_are_recorded_as_loaded() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let server = add_server(&state, Server::new(image_backend().await)).await;
+        let loaded = || server.models.read().unwrap().loaded.clone();
+
+        for (body, expected) in [
+            // restored afterwards by default
+            (
+                r#"{"prompt": "a cat", "override_settings": {"sd_model_checkpoint": "anime"}}"#,
+                None,
+            ),
+            (
+                r#"{"prompt": "a cat", "override_settings": {"sd_model_checkpoint": "anime"}, "override_settings_restore_afterwards": true}"#,
+                None,
+            ),
+            (
+                r#"{"prompt": "a cat", "override_settings": {"sd_model_checkpoint": "anime"}, "override_settings_restore_afterwards": false}"#,
+                Some("anime".to_string()),
+            ),
+        ] {
+            let req = generation_request(&state, "/v1/images/generations", body);
+            let response = proxy_request(state.clone(), req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(loaded(), expected, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn url_responses_are_saved_in_the_image_store() {
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.image_store = Some(Arc::new(MemoryImageStore::default()));
+        add_server(&state, Server::new(image_backend().await)).await;
+
+        let req = generation_request(
+            &state,
+            "/v1/images/generations",
+            r#"{"prompt": "a cat", "response_format": "url"}"#,
+        );
+        let response = proxy_request(state.clone(), req).await.unwrap();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let url = response["data"][0]["url"].as_str().unwrap();
+        assert!(response["data"][0].get("b64_json").is_none());
+        let id = url
+            .strip_prefix("http://images.test/v1/images/files/")
+            .unwrap();
+
+        let response = image_file_handler(State(state), Path(id.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "hello");
+    }
 }
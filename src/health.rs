@@ -0,0 +1,191 @@
+use crate::{utils::unix_timestamp, AppState, Server, SharedClient};
+use hyper::Uri;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+/// Path probed on every downstream server. It is cheap to serve and does not touch the GPU.
+const PROBE_ENDPOINT: &str = "sdapi/v1/progress?skip_current_image=true";
+
+/// Options of the background health checker
+#[derive(Debug, Clone)]
+pub(crate) struct HealthCheckConfig {
+    /// Interval between two rounds of probes. Health checking is disabled if it is zero.
+    pub(crate) interval: Duration,
+    /// Timeout of a single probe
+    pub(crate) timeout: Duration,
+    /// Number of consecutive failed probes before a server is marked unhealthy
+    pub(crate) unhealthy_threshold: usize,
+    /// Number of consecutive successful probes before an unhealthy server is marked healthy again
+    pub(crate) healthy_threshold: usize,
+}
+
+/// Spawns a task that periodically probes every registered downstream server.
+pub(crate) fn spawn_health_checker(state: AppState, config: HealthCheckConfig) {
+    if config.interval.is_zero() {
+        info!(target: "stdout", "health checking is disabled");
+        return;
+    }
+
+    info!(target: "stdout", "health checking every {} seconds", config.interval.as_secs());
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+
+            let servers: Vec<Arc<Server>> =
+                state.image_urls.read().await.servers.read().await.clone();
+
+            let probes = servers
+                .iter()
+                .map(|server| probe(&state.client, server, &config));
+            futures_util::future::join_all(probes).await;
+        }
+    });
+}
+
+async fn probe(client: &SharedClient, server: &Server, config: &HealthCheckConfig) {
+    let probe_uri: Uri = match format!(
+        "{}/{}",
+        server.url.to_string().trim_end_matches('/'),
+        PROBE_ENDPOINT
+    )
+    .parse()
+    {
+        Ok(uri) => uri,
+        Err(e) => {
+            error!(target: "stdout", "invalid probe url for {}: {}", server.url, e);
+            return;
+        }
+    };
+
+    let success = match tokio::time::timeout(config.timeout, client.get(probe_uri)).await {
+        Ok(Ok(response)) if response.status().is_success() => true,
+        Ok(Ok(response)) => {
+            warn!(target: "stdout", "probe of {} returned {}", server.url, response.status());
+            false
+        }
+        Ok(Err(e)) => {
+            warn!(target: "stdout", "probe of {} failed: {}", server.url, e);
+            false
+        }
+        Err(_) => {
+            warn!(target: "stdout", "probe of {} timed out", server.url);
+            false
+        }
+    };
+
+    server.record_probe(success, config);
+}
+
+impl Server {
+    /// Records the result of a probe and updates the health state once a threshold is reached.
+    fn record_probe(&self, success: bool, config: &HealthCheckConfig) {
+        self.last_probe.store(unix_timestamp(), Ordering::Relaxed);
+
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            let successes = self.consecutive_successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if successes >= config.healthy_threshold && !self.healthy.swap(true, Ordering::Relaxed)
+            {
+                info!(target: "stdout", "server {} is healthy again", self.url);
+            }
+        } else {
+            self.consecutive_successes.store(0, Ordering::Relaxed);
+            let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= config.unhealthy_threshold && self.healthy.swap(false, Ordering::Relaxed)
+            {
+                warn!(target: "stdout", "server {} is unhealthy after {} failed probes", self.url, failures);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_backend;
+    use hyper::{Body, Client, Response, StatusCode};
+
+    fn config(unhealthy_threshold: usize, healthy_threshold: usize) -> HealthCheckConfig {
+        HealthCheckConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_millis(200),
+            unhealthy_threshold,
+            healthy_threshold,
+        }
+    }
+
+    #[test]
+    fn servers_turn_unhealthy_after_consecutive_failures() {
+        let config = config(3, 2);
+        let server = Server::new("http://a:7860".parse().unwrap());
+
+        server.record_probe(false, &config);
+        server.record_probe(false, &config);
+        assert!(server.is_healthy());
+
+        // a success resets the count
+        server.record_probe(true, &config);
+        server.record_probe(false, &config);
+        server.record_probe(false, &config);
+        assert!(server.is_healthy());
+
+        server.record_probe(false, &config);
+        assert!(!server.is_healthy());
+        assert_ne!(server.last_probe.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn servers_recover_after_consecutive_successes() {
+        let config = config(1, 3);
+        let server = Server::new("http://a:7860".parse().unwrap());
+        server.record_probe(false, &config);
+        assert!(!server.is_healthy());
+
+        server.record_probe(true, &config);
+        server.record_probe(true, &config);
+        assert!(!server.is_healthy());
+
+        // a failure resets the count
+        server.record_probe(false, &config);
+        server.record_probe(true, &config);
+        server.record_probe(true, &config);
+        assert!(!server.is_healthy());
+
+        server.record_probe(true, &config);
+        assert!(server.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn probes_fail_on_error_statuses_and_timeouts() {
+        let client: SharedClient = Arc::new(Client::new());
+        let config = config(1, 1);
+
+        let ok = mock_backend(|req| async move {
+            assert_eq!(req.uri(), "/sdapi/v1/progress?skip_current_image=true");
+            Response::new(Body::empty())
+        })
+        .await;
+        let failing = mock_backend(|_| async {
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await;
+        let hanging = mock_backend(|_| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Response::new(Body::empty())
+        })
+        .await;
+
+        for (url, healthy) in [(ok, true), (failing, false), (hanging, false)] {
+            let server = Server::new(url.clone());
+            probe(&client, &server, &config).await;
+            assert_eq!(server.is_healthy(), healthy, "{}", url);
+        }
+    }
+}
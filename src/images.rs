@@ -0,0 +1,713 @@
+use crate::output::OutputFormat;
+use base64::{engine::general_purpose, Engine as _};
+use endpoints::images::ImageObject;
+use image::{GrayImage, ImageFormat, Luma};
+use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
+use multipart_2021 as multipart;
+use serde::Serialize;
+use std::io::{Cursor, Read};
+
+/// Maximum number of images that can be requested in a single call
+pub(crate) const MAX_IMAGES_PER_REQUEST: u32 = 10;
+
+/// How the generated images are returned to the client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ResponseFormat {
+    /// Embedded in the response, base64 encoded
+    #[default]
+    B64Json,
+    /// Kept in the image store, and returned as URLs
+    Url,
+}
+/// How the generated images are encoded and returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ImageOutput {
+    pub(crate) response_format: ResponseFormat,
+    /// Format the images are converted to. The PNGs of SD WebUI are returned as is if `None`.
+    pub(crate) format: Option<OutputFormat>,
+    /// Quality of the JPEG images, between 0 and 100
+    pub(crate) compression: Option<u8>,
+    /// Whether to add the generation info, e.g. the seed, to every image
+    pub(crate) include_info: bool,
+}
+impl ImageOutput {
+    fn parse_compression(compression: &str) -> Result<u8, String> {
+        compression
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|compression| *compression <= 100)
+            .ok_or_else(|| {
+                format!(
+                    "`output_compression` must be an integer between 0 and 100, but got {}",
+                    compression
+                )
+            })
+    }
+
+    /// Rejects the options that cannot be honored. WebP images are always lossless, so a
+    /// quality would be silently ignored.
+    fn validate(&self) -> Result<(), String> {
+        if self.format == Some(OutputFormat::Webp) && self.compression.is_some() {
+            return Err(
+                "`output_compression` is not supported with the lossless `webp`".to_string(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "b64_json" => Ok(ResponseFormat::B64Json),
+            "url" => Ok(ResponseFormat::Url),
+            _ => Err(format!(
+                "Unsupported `response_format` `{}`. Expected `b64_json` or `url`",
+                s
+            )),
+        }
+    }
+}
+
+/// An OpenAI-style image edit request parsed from a `multipart/form-data` body
+#[derive(Debug, Default)]
+pub(crate) struct ImageEditRequest {
+    /// The image to edit, base64 encoded
+    pub(crate) image: String,
+    /// Optional mask in the convention of SD WebUI, base64 encoded. White pixels mark the area
+    /// to repaint.
+    pub(crate) mask: Option<String>,
+    /// A text description of the desired image
+    pub(crate) prompt: String,
+    /// A text description of what the image should not contain
+    pub(crate) negative_prompt: Option<String>,
+    /// Number of images to generate
+    pub(crate) n: Option<u32>,
+    /// Size of the generated images, as `(width, height)`
+    pub(crate) size: Option<(u32, u32)>,
+    /// Whether to stream the progress as Server-Sent Events
+    pub(crate) stream: bool,
+    pub(crate) output: ImageOutput,
+}
+impl ImageEditRequest {
+    /// Parses the multipart body of an image edit request.
+    pub(crate) fn from_multipart(content_type: &str, body: &[u8]) -> Result<Self, String> {
+        let boundary = multipart_boundary(content_type).ok_or_else(|| {
+            format!(
+                "Expected a multipart/form-data request with a boundary, but got `{}`",
+                content_type
+            )
+        })?;
+
+        let mut request = ImageEditRequest::default();
+        let mut image = None;
+        let mut prompt = None;
+
+        let mut multipart = Multipart::with_body(Cursor::new(body), boundary);
+        loop {
+            let mut field = match multipart.read_entry_mut() {
+                ReadEntryResult::Entry(field) => field,
+                ReadEntryResult::End(_) => break,
+                ReadEntryResult::Error(_, e) => {
+                    return Err(format!("Failed to read the multipart body. {}", e))
+                }
+            };
+
+            let mut data = Vec::new();
+            let name = field.headers.name.to_string();
+            field
+                .data
+                .read_to_end(&mut data)
+                .map_err(|e| format!("Failed to read the `{}` field. {}", name, e))?;
+
+            match name.as_str() {
+                "image" => image = Some(general_purpose::STANDARD.encode(&data)),
+                "mask" => {
+                    request.mask = Some(general_purpose::STANDARD.encode(convert_mask(&data)?))
+                }
+                "prompt" => prompt = Some(field_to_string(&name, data)?),
+                "negative_prompt" => request.negative_prompt = Some(field_to_string(&name, data)?),
+                "n" => {
+                    let n = field_to_string(&name, data)?;
+                    let n = n
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid `n`: {}", n))?;
+                    if n == 0 || n > MAX_IMAGES_PER_REQUEST {
+                        return Err(format!(
+                            "`n` must be between 1 and {}, but got {}",
+                            MAX_IMAGES_PER_REQUEST, n
+                        ));
+                    }
+                    request.n = Some(n);
+                }
+                "size" => request.size = Some(parse_size(&field_to_string(&name, data)?)?),
+                "response_format" => {
+                    request.output.response_format = field_to_string(&name, data)?.trim().parse()?
+                }
+                "output_format" => {
+                    request.output.format = Some(field_to_string(&name, data)?.trim().parse()?)
+                }
+                "output_compression" => {
+                    request.output.compression = Some(ImageOutput::parse_compression(
+                        &field_to_string(&name, data)?,
+                    )?)
+                }
+                "stream" => {
+                    let stream = field_to_string(&name, data)?;
+                    request.stream = stream
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid `stream`: {}", stream))?;
+                }
+                "include_info" => {
+                    let include_info = field_to_string(&name, data)?;
+                    request.output.include_info = include_info
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid `include_info`: {}", include_info))?;
+                }
+                _ => warn!(target: "stdout", "Ignore the unsupported field `{}`", name),
+            }
+        }
+        request.output.validate()?;
+
+        request.image = image.ok_or("Missing the `image` field")?;
+        request.prompt = prompt.ok_or("Missing the `prompt` field")?;
+
+        Ok(request)
+    }
+
+    /// Translates the request into a payload for the `/sdapi/v1/img2img` endpoint of SD WebUI.
+    pub(crate) fn to_img2img_payload(&self) -> serde_json::Value {
+        let mut payload = serde_json::json!({
+            "init_images": [self.image],
+            "prompt": self.prompt,
+        });
+
+        if let Some(mask) = &self.mask {
+            payload["mask"] = serde_json::json!(mask);
+        }
+        if let Some(negative_prompt) = &self.negative_prompt {
+            payload["negative_prompt"] = serde_json::json!(negative_prompt);
+        }
+        if let Some(n) = self.n {
+            payload["batch_size"] = serde_json::json!(n);
+        }
+        if let Some((width, height)) = self.size {
+            payload["width"] = serde_json::json!(width);
+            payload["height"] = serde_json::json!(height);
+        }
+
+        payload
+    }
+}
+
+/// Converts an OpenAI mask, whose fully transparent pixels mark the area to edit, into a mask
+/// of SD WebUI, whose white pixels mark the area to repaint. Masks without transparent pixels
+/// are taken as SD WebUI masks already, and returned as is.
+fn convert_mask(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mask = image::load_from_memory(data).map_err(|e| format!("Invalid `mask`. {}", e))?;
+    if !mask.color().has_alpha() {
+        return Ok(data.to_vec());
+    }
+
+    let mask = mask.to_rgba8();
+    if mask.pixels().all(|pixel| pixel[3] == u8::MAX) {
+        return Ok(data.to_vec());
+    }
+
+    let converted = GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        match mask.get_pixel(x, y)[3] {
+            0 => Luma([u8::MAX]),
+            _ => Luma([0]),
+        }
+    });
+    let mut png = Vec::new();
+    converted
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| format!("Failed to convert the `mask`. {}", e))?;
+
+    Ok(png)
+}
+
+/// Translates the fields of an OpenAI `CreateImageRequest` into the fields of a txt2img request
+/// of SD WebUI, in place. The txt2img fields set explicitly in the request take precedence.
+///
+/// - `n` sets `batch_size`
+/// - `size` sets `width` and `height`
+/// - `quality` sets `steps`, and enables hires fix for `hd` and `high`
+/// - `style` sets `cfg_scale`
+/// - `user` is ignored
+/// - `output_format`, `output_compression` and `include_info` are checked, and left to the proxy
+///
+/// Returns the `response_format`, `output_format`, `output_compression` and `include_info` of
+/// the request.
+pub(crate) fn translate_create_image_request(
+    request: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<ImageOutput, String> {
+    let n = request.remove("n");
+    let size = request.remove("size");
+    let quality = request.remove("quality");
+    let style = request.remove("style");
+    let response_format = request.remove("response_format");
+    let output_format = request.remove("output_format");
+    let output_compression = request.remove("output_compression");
+    let include_info = request.remove("include_info");
+    if let Some(user) = request.remove("user") {
+        info!(target: "stdout", "end user of the image request: {}", user);
+    }
+
+    let mut set_default = |field: &str, value: serde_json::Value| {
+        request.entry(field).or_insert(value);
+    };
+
+    if let Some(n) = n.filter(|n| !n.is_null()) {
+        let n = n
+            .as_u64()
+            .filter(|n| (1..=MAX_IMAGES_PER_REQUEST as u64).contains(n))
+            .ok_or_else(|| {
+                format!(
+                    "`n` must be an integer between 1 and {}, but got {}",
+                    MAX_IMAGES_PER_REQUEST, n
+                )
+            })?;
+        set_default("batch_size", n.into());
+    }
+
+    match size.as_ref().map(|size| (size, size.as_str())) {
+        None | Some((serde_json::Value::Null, _)) | Some((_, Some("auto"))) => {}
+        Some((_, Some(size))) => {
+            let (width, height) = parse_size(size)?;
+            set_default("width", width.into());
+            set_default("height", height.into());
+        }
+        Some((size, None)) => return Err(format!("`size` must be a string, but got {}", size)),
+    }
+
+    match quality.as_ref().map(|quality| (quality, quality.as_str())) {
+        None | Some((serde_json::Value::Null, _)) | Some((_, Some("auto"))) => {}
+        Some((_, Some("low"))) => set_default("steps", 15.into()),
+        Some((_, Some("standard" | "medium"))) => set_default("steps", 25.into()),
+        Some((_, Some("hd" | "high"))) => {
+            set_default("steps", 40.into());
+            set_default("enable_hr", true.into());
+            set_default("hr_scale", 1.5.into());
+            set_default("denoising_strength", 0.5.into());
+        }
+        Some((quality, _)) => {
+            return Err(format!(
+                "Unsupported `quality` {}. Expected one of `standard`, `hd`, `low`, `medium`, `high` and `auto`",
+                quality
+            ))
+        }
+    }
+
+    match style.as_ref().map(|style| (style, style.as_str())) {
+        None | Some((serde_json::Value::Null, _)) => {}
+        Some((_, Some("vivid"))) => set_default("cfg_scale", 9.into()),
+        Some((_, Some("natural"))) => set_default("cfg_scale", 5.into()),
+        Some((style, _)) => {
+            return Err(format!(
+                "Unsupported `style` {}. Expected `vivid` or `natural`",
+                style
+            ))
+        }
+    }
+
+    let mut output = ImageOutput::default();
+    match response_format {
+        None | Some(serde_json::Value::Null) => {}
+        Some(serde_json::Value::String(format)) => output.response_format = format.parse()?,
+        Some(format) => {
+            return Err(format!(
+                "`response_format` must be a string, but got {}",
+                format
+            ))
+        }
+    }
+    match output_format {
+        None | Some(serde_json::Value::Null) => {}
+        Some(serde_json::Value::String(format)) => output.format = Some(format.parse()?),
+        Some(format) => {
+            return Err(format!(
+                "`output_format` must be a string, but got {}",
+                format
+            ))
+        }
+    }
+    match output_compression {
+        None | Some(serde_json::Value::Null) => {}
+        Some(compression) => {
+            output.compression = Some(ImageOutput::parse_compression(&compression.to_string())?)
+        }
+    }
+    match include_info {
+        None | Some(serde_json::Value::Null) => {}
+        Some(serde_json::Value::Bool(include_info)) => output.include_info = include_info,
+        Some(include_info) => {
+            return Err(format!(
+                "`include_info` must be a boolean, but got {}",
+                include_info
+            ))
+        }
+    }
+    output.validate()?;
+
+    Ok(output)
+}
+
+/// Response of the image endpoints, in the shape of the OpenAI `ImagesResponse`.
+///
+/// It serializes as `endpoints::images::ListImagesResponse` does, whose `data` only holds
+/// `ImageObject`s and cannot carry the generation info of the images.
+#[derive(Debug, Serialize)]
+pub(crate) struct ImagesResponse {
+    pub(crate) created: u64,
+    pub(crate) data: Vec<GeneratedImage>,
+}
+
+/// An image returned to the client, with its generation info if the client asked for it
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct GeneratedImage {
+    #[serde(flatten)]
+    pub(crate) image: ImageObject,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub(crate) info: Option<GenerationInfo>,
+}
+
+/// Parameters SD WebUI actually used to generate an image, e.g. the seed resolved from `-1`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct GenerationInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) subseed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sampler: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) steps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cfg_scale: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+}
+
+/// Reads the generation info of every image of a txt2img or img2img response of SD WebUI.
+///
+/// The `info` field of the response is a JSON string holding the parameters of the batch,
+/// and the seeds of its images in `all_seeds`. Images that are not part of the batch, e.g. the
+/// grid listed before the batch or the detected maps of ControlNet listed after it, get `None`.
+pub(crate) fn parse_generation_info(
+    response: &serde_json::Value,
+    num_images: usize,
+) -> Vec<Option<GenerationInfo>> {
+    let info = match response.get("info") {
+        Some(serde_json::Value::String(info)) => match serde_json::from_str(info) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!(target: "stdout", "failed to parse the generation info: {}", e);
+                return vec![None; num_images];
+            }
+        },
+        Some(info @ serde_json::Value::Object(_)) => info.clone(),
+        _ => return vec![None; num_images],
+    };
+
+    let str_field = |field: &str| info.get(field).and_then(|v| v.as_str()).map(String::from);
+    let per_image = |field: &str, index: usize| {
+        info.get(field)
+            .and_then(|v| v.as_array())
+            .and_then(|values| values.get(index))
+            .and_then(|v| v.as_i64())
+    };
+    let first = info
+        .get("index_of_first_image")
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default() as usize;
+    let batch_len = info
+        .get("all_seeds")
+        .and_then(|v| v.as_array())
+        .map_or(0, |seeds| seeds.len());
+
+    (0..num_images)
+        .map(|i| {
+            if i < first || i - first >= batch_len {
+                return None;
+            }
+
+            let index = i - first;
+            Some(GenerationInfo {
+                seed: per_image("all_seeds", index),
+                subseed: per_image("all_subseeds", index),
+                sampler: str_field("sampler_name"),
+                steps: info.get("steps").and_then(|v| v.as_u64()),
+                cfg_scale: info.get("cfg_scale").and_then(|v| v.as_f64()),
+                model: str_field("sd_model_name").or_else(|| str_field("sd_model_hash")),
+            })
+        })
+        .collect()
+}
+
+/// Parses an image size in the form of `{width}x{height}`, e.g. `512x512`.
+pub(crate) fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    let err_msg = || format!("Invalid size `{}`. Expected `{{width}}x{{height}}`", size);
+
+    let (width, height) = size.trim().split_once('x').ok_or_else(err_msg)?;
+    let width: u32 = width.parse().map_err(|_| err_msg())?;
+    let height: u32 = height.parse().map_err(|_| err_msg())?;
+    if width == 0 || height == 0 || !width.is_multiple_of(8) || !height.is_multiple_of(8) {
+        return Err(format!(
+            "Invalid size `{}`. Width and height must be positive multiples of 8",
+            size
+        ));
+    }
+
+    Ok((width, height))
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        match key.trim().eq_ignore_ascii_case("boundary") {
+            true => Some(value.trim().trim_matches('"').to_string()),
+            false => None,
+        }
+    })
+}
+
+fn field_to_string(name: &str, data: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(data).map_err(|_| format!("The `{}` field is not valid UTF-8", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn encode_png(image: impl Into<image::DynamicImage>) -> Vec<u8> {
+        let mut png = Vec::new();
+        image
+            .into()
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn transparent_pixels_of_openai_masks_become_white() {
+        // the left half is transparent, i.e. to edit
+        let mask = RgbaImage::from_fn(4, 2, |x, _| match x < 2 {
+            true => Rgba([0, 0, 0, 0]),
+            false => Rgba([255, 255, 255, 255]),
+        });
+
+        let converted = convert_mask(&encode_png(mask)).unwrap();
+        let converted = image::load_from_memory(&converted).unwrap().to_luma8();
+        assert_eq!(converted.dimensions(), (4, 2));
+        for (x, _, pixel) in converted.enumerate_pixels() {
+            assert_eq!(pixel[0], if x < 2 { 255 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn masks_without_transparency_are_passed_as_is() {
+        let grayscale = encode_png(GrayImage::from_fn(4, 2, |x, _| Luma([(x * 60) as u8])));
+        assert_eq!(convert_mask(&grayscale).unwrap(), grayscale);
+
+        let opaque = encode_png(RgbaImage::from_pixel(4, 2, Rgba([255, 255, 255, 255])));
+        assert_eq!(convert_mask(&opaque).unwrap(), opaque);
+
+        assert!(convert_mask(b"not an image").is_err());
+    }
+
+    /// Translates an OpenAI request, returning the txt2img body and the output options.
+    fn translate(
+        mut request: serde_json::Value,
+    ) -> Result<(serde_json::Value, ImageOutput), String> {
+        let output = translate_create_image_request(request.as_object_mut().unwrap())?;
+        Ok((request, output))
+    }
+
+    #[test]
+    fn openai_fields_become_txt2img_fields() {
+        let (body, output) = translate(serde_json::json!({
+            "prompt": "a cat",
+            "n": 2,
+            "size": "1024x768",
+            "quality": "hd",
+            "style": "natural",
+            "user": "user-1",
+            "response_format": "b64_json",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "prompt": "a cat",
+                "batch_size": 2,
+                "width": 1024,
+                "height": 768,
+                "steps": 40,
+                "enable_hr": true,
+                "hr_scale": 1.5,
+                "denoising_strength": 0.5,
+                "cfg_scale": 5,
+            })
+        );
+        assert_eq!(output.response_format, ResponseFormat::B64Json);
+    }
+
+    #[test]
+    fn quality_and_style_map_to_steps_and_cfg_scale() {
+        for (quality, steps) in [("low", 15), ("standard", 25), ("medium", 25), ("high", 40)] {
+            let (body, _) = translate(serde_json::json!({"quality": quality})).unwrap();
+            assert_eq!(body["steps"], steps, "{}", quality);
+        }
+        for (style, cfg_scale) in [("vivid", 9), ("natural", 5)] {
+            let (body, _) = translate(serde_json::json!({"style": style})).unwrap();
+            assert_eq!(body["cfg_scale"], cfg_scale, "{}", style);
+        }
+    }
+
+    #[test]
+    fn auto_and_null_keep_the_defaults() {
+        let (body, output) = translate(serde_json::json!({
+            "prompt": "a cat",
+            "n": null,
+            "size": "auto",
+            "quality": "auto",
+            "style": null,
+        }))
+        .unwrap();
+
+        assert_eq!(body, serde_json::json!({"prompt": "a cat"}));
+        assert_eq!(output.response_format, ResponseFormat::default());
+    }
+
+    #[test]
+    fn explicit_txt2img_fields_take_precedence() {
+        let (body, _) = translate(serde_json::json!({
+            "n": 4,
+            "batch_size": 1,
+            "size": "512x512",
+            "width": 768,
+            "quality": "low",
+            "steps": 30,
+        }))
+        .unwrap();
+
+        assert_eq!(body["batch_size"], 1);
+        assert_eq!(body["width"], 768);
+        assert_eq!(body["height"], 512);
+        assert_eq!(body["steps"], 30);
+    }
+
+    #[test]
+    fn invalid_openai_fields_are_rejected() {
+        for request in [
+            serde_json::json!({"n": 0}),
+            serde_json::json!({"n": 11}),
+            serde_json::json!({"n": "2"}),
+            serde_json::json!({"n": 1.5}),
+            serde_json::json!({"size": "1024"}),
+            serde_json::json!({"size": "1024x"}),
+            serde_json::json!({"size": "0x512"}),
+            serde_json::json!({"size": "1020x512"}),
+            serde_json::json!({"size": 1024}),
+            serde_json::json!({"quality": "ultra"}),
+            serde_json::json!({"style": "cartoon"}),
+            serde_json::json!({"response_format": "png"}),
+            serde_json::json!({"output_format": "gif"}),
+            serde_json::json!({"output_compression": 101}),
+            serde_json::json!({"include_info": "yes"}),
+        ] {
+            assert!(translate(request.clone()).is_err(), "{}", request);
+        }
+    }
+
+    #[test]
+    fn sizes_are_multiples_of_8() {
+        assert_eq!(parse_size("1024x768"), Ok((1024, 768)));
+        assert_eq!(parse_size(" 512x512 "), Ok((512, 512)));
+        assert!(parse_size("512x513").is_err());
+        assert!(parse_size("512X512").is_err());
+        assert!(parse_size("-8x8").is_err());
+    }
+
+    #[test]
+    fn generation_info_is_read_per_image() {
+        // a grid, a batch of 2, then a ControlNet map
+        let info = serde_json::json!({
+            "all_seeds": [1234, 1235],
+            "all_subseeds": [99, 100],
+            "index_of_first_image": 1,
+            "sampler_name": "Euler a",
+            "steps": 25,
+            "cfg_scale": 7.5,
+            "sd_model_name": "anime",
+            "sd_model_hash": "31e35c80fc",
+        });
+        let response = serde_json::json!({"info": info.to_string()});
+
+        let infos = parse_generation_info(&response, 4);
+        assert!(infos[0].is_none());
+        assert!(infos[3].is_none());
+        for (info, (seed, subseed)) in infos[1..3].iter().zip([(1234, 99), (1235, 100)]) {
+            let info = info.as_ref().unwrap();
+            assert_eq!((info.seed, info.subseed), (Some(seed), Some(subseed)));
+            assert_eq!(info.sampler.as_deref(), Some("Euler a"));
+            assert_eq!(info.steps, Some(25));
+            assert_eq!(info.cfg_scale, Some(7.5));
+            assert_eq!(info.model.as_deref(), Some("anime"));
+        }
+
+        // an object instead of a string, falling back to the hash of the model
+        let response =
+            serde_json::json!({"info": {"all_seeds": [7], "sd_model_hash": "31e35c80fc"}});
+        let infos = parse_generation_info(&response, 1);
+        let info = infos[0].as_ref().unwrap();
+        assert_eq!(info.seed, Some(7));
+        assert_eq!(info.subseed, None);
+        assert_eq!(info.model.as_deref(), Some("31e35c80fc"));
+    }
+
+    #[test]
+    fn missing_or_malformed_info_gives_no_info() {
+        for response in [
+            serde_json::json!({}),
+            serde_json::json!({"info": null}),
+            serde_json::json!({"info": 42}),
+            serde_json::json!({"info": "not json"}),
+            serde_json::json!({"info": "{}"}),
+            serde_json::json!({"info": {"all_seeds": "1234"}}),
+        ] {
+            let infos = parse_generation_info(&response, 2);
+            assert!(infos.iter().all(|info| info.is_none()), "{}", response);
+            assert_eq!(infos.len(), 2);
+        }
+    }
+
+    #[test]
+    fn webp_rejects_a_compression() {
+        assert!(translate(serde_json::json!({"output_format": "webp"})).is_ok());
+        assert!(
+            translate(serde_json::json!({"output_format": "jpeg", "output_compression": 50}))
+                .is_ok()
+        );
+        let error =
+            translate(serde_json::json!({"output_format": "webp", "output_compression": 50}))
+                .unwrap_err();
+        assert!(error.contains("`output_compression`"), "{}", error);
+    }
+}
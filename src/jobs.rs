@@ -0,0 +1,274 @@
+use crate::{images::GeneratedImage, utils::unix_timestamp};
+use hyper::Uri;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, task::AbortHandle};
+
+/// Longest interval between two sweeps of the expired jobs
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Status of an asynchronous generation job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    /// Waiting for a downstream server
+    Queued,
+    /// Dispatched to a downstream server
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// An asynchronous generation job and its result
+#[derive(Debug, Serialize)]
+struct Job {
+    id: String,
+    object: &'static str,
+    status: JobStatus,
+    /// Unix timestamp of the submission
+    created: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    /// Unix timestamp after which the result is discarded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Vec<GeneratedImage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JobError>,
+    /// API key of the client that submitted the job
+    #[serde(skip)]
+    owner: Option<String>,
+    /// Downstream server running the job
+    #[serde(skip)]
+    server: Option<Uri>,
+    /// Handle to abort the task running the job
+    #[serde(skip)]
+    task: Option<AbortHandle>,
+    #[serde(skip)]
+    expires: Option<Instant>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobError {
+    message: String,
+}
+
+/// State of a job at a point in time
+#[derive(Debug)]
+pub(crate) struct JobSnapshot {
+    pub(crate) status: JobStatus,
+    /// Downstream server running the job, once it is dispatched
+    pub(crate) server: Option<Uri>,
+    /// JSON representation of the job
+    pub(crate) job: serde_json::Value,
+}
+
+/// Outcome of `JobStore::cancel`
+#[derive(Debug)]
+pub(crate) struct Cancellation {
+    /// JSON representation of the job
+    pub(crate) job: serde_json::Value,
+    /// Downstream server whose generation was aborted, if the job was running
+    pub(crate) aborted_on: Option<Uri>,
+}
+
+/// In-memory store of the asynchronous generation jobs. Finished jobs are kept for `ttl`.
+#[derive(Debug)]
+pub(crate) struct JobStore {
+    jobs: Mutex<HashMap<String, Job>>,
+    ttl: Duration,
+    /// Woken up whenever a job changes status
+    notify: Notify,
+}
+impl JobStore {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Creates a queued job and returns its id.
+    pub(crate) fn create(&self, owner: Option<String>) -> String {
+        let id = format!("job-{}", uuid::Uuid::new_v4().simple());
+        let job = Job {
+            id: id.clone(),
+            object: "image.generation.job",
+            status: JobStatus::Queued,
+            created: unix_timestamp(),
+            started_at: None,
+            finished_at: None,
+            expires_at: None,
+            data: None,
+            error: None,
+            owner,
+            server: None,
+            task: None,
+            expires: None,
+        };
+        self.jobs.lock().unwrap().insert(id.clone(), job);
+
+        id
+    }
+
+    /// Attaches the task running a job, so that the job can be cancelled.
+    pub(crate) fn attach(&self, id: &str, task: AbortHandle) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            if job.is_finished() {
+                return;
+            }
+            job.task = Some(task);
+        }
+    }
+
+    /// Marks a job as running on a downstream server.
+    pub(crate) fn set_running(&self, id: &str, server: &Uri) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.status = JobStatus::Running;
+            job.server = Some(server.clone());
+            job.started_at.get_or_insert_with(unix_timestamp);
+            info!(target: "stdout", "job {} is running on {}", id, server);
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Records the result of a job and starts its expiry countdown.
+    pub(crate) fn finish(&self, id: &str, result: Result<Vec<GeneratedImage>, String>) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            // the job may have been cancelled in the meantime
+            if job.is_finished() {
+                return;
+            }
+
+            job.task = None;
+            match result {
+                Ok(images) => {
+                    job.status = JobStatus::Succeeded;
+                    job.data = Some(images);
+                }
+                Err(message) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(JobError { message });
+                }
+            }
+            job.set_finished(self.ttl);
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Cancels a job and aborts its task, which frees the slot of the downstream server running
+    /// it, and interrupts the server if it runs a single request at a time. A finished job is left as is.
+    ///
+    /// Returns the cancelled job, or `None` if the job does not exist, has expired or belongs to
+    /// another API key.
+    pub(crate) fn cancel(&self, id: &str, owner: Option<&str>) -> Option<Cancellation> {
+        let cancellation = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(id)?;
+            if job.owner.as_deref() != owner || job.is_expired() {
+                return None;
+            }
+
+            let mut aborted_on = None;
+            if !job.is_finished() {
+                if job.status == JobStatus::Running {
+                    aborted_on = job.server.clone();
+                }
+                job.status = JobStatus::Cancelled;
+                job.set_finished(self.ttl);
+                if let Some(task) = job.task.take() {
+                    task.abort();
+                }
+                info!(target: "stdout", "cancelled job {}", id);
+            }
+
+            Cancellation {
+                job: serde_json::to_value(&*job).ok()?,
+                aborted_on,
+            }
+        };
+        self.notify.notify_waiters();
+
+        Some(cancellation)
+    }
+
+    /// Returns the JSON representation of a job, or `None` if the job does not exist, has
+    /// expired or belongs to another API key.
+    pub(crate) fn get(&self, id: &str, owner: Option<&str>) -> Option<serde_json::Value> {
+        self.snapshot(id, owner).map(|snapshot| snapshot.job)
+    }
+
+    /// Same as `get`, along with the status and the downstream server of the job.
+    pub(crate) fn snapshot(&self, id: &str, owner: Option<&str>) -> Option<JobSnapshot> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        if job.owner.as_deref() != owner || job.is_expired() {
+            return None;
+        }
+
+        Some(JobSnapshot {
+            status: job.status,
+            server: job.server.clone(),
+            job: serde_json::to_value(job).ok()?,
+        })
+    }
+
+    /// Returns a future that completes the next time a job changes status.
+    ///
+    /// The future must be created before reading the job so that no change is missed.
+    pub(crate) fn changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.notify.notified()
+    }
+
+    /// Removes the finished jobs whose TTL has elapsed.
+    pub(crate) fn purge_expired(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| !job.is_expired());
+        if jobs.len() < before {
+            info!(target: "stdout", "discarded {} expired jobs", before - jobs.len());
+        }
+    }
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+
+    fn set_finished(&mut self, ttl: Duration) {
+        let now = unix_timestamp();
+        self.finished_at = Some(now);
+        self.expires_at = Some(now + ttl.as_secs());
+        self.expires = Some(Instant::now() + ttl);
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= Instant::now())
+    }
+}
+
+/// Spawns a task that periodically discards the expired jobs.
+pub(crate) fn spawn_job_sweeper(jobs: Arc<JobStore>) {
+    let interval = jobs.ttl.min(MAX_SWEEP_INTERVAL);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            jobs.purge_expired();
+        }
+    });
+}
@@ -1,44 +1,190 @@
 #[macro_use]
 extern crate log;
 
+mod api_keys;
+mod auth;
+mod config;
+mod discovery;
 mod error;
 mod handler;
+mod health;
+mod images;
+mod jobs;
+mod models;
+mod output;
+mod policy;
+mod progress;
+mod queue;
+mod rate_limit;
+mod registry;
+mod s3;
+mod storage;
 mod utils;
 
 use anyhow::Result;
+use api_keys::ApiKeyStore;
 use async_trait::async_trait;
-use axum::{http::Uri, routing::post, Router};
+use axum::{
+    http::Uri,
+    middleware,
+    routing::{get, post},
+    Router,
+};
 use clap::{ArgGroup, Parser};
+use config::{BackendConfig, Config};
+use discovery::{Capabilities, ModelMatch, ServerModels};
 use error::ServerError;
 use handler::*;
+use health::HealthCheckConfig;
 use hyper::{client::HttpConnector, Client};
+use jobs::JobStore;
+use models::ModelCache;
+use output::{MetadataPolicy, PngMetadata};
+use policy::Balancer;
+use queue::{QueueConfig, QueueReport, RequestQueue};
+use rate_limit::{RateLimit, RateLimiter};
+use registry::{RegisteredServer, RegistryFile, RegistrySnapshot};
+use s3::S3ImageStore;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt,
     net::SocketAddr,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
+use storage::{ImageStore, LocalImageStore};
 use tokio::{net::TcpListener, sync::RwLock};
 use utils::LogLevel;
 
 type SharedClient = Arc<Client<HttpConnector>>;
 
 // default port of SD-Proxy-Server
-const DEFAULT_PORT: &str = "8080";
+const DEFAULT_PORT: u16 = 8080;
+// default interval in seconds between health checks
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+// default weight of a downstream server
+const DEFAULT_WEIGHT: u32 = 1;
+// default interval in seconds between two discoveries of the capabilities of the servers
+const DEFAULT_DISCOVERY_INTERVAL: u64 = 300;
+// default timeout in seconds of a single health check
+const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 5;
+// default number of failed health checks before a server is marked unhealthy
+const DEFAULT_UNHEALTHY_THRESHOLD: u64 = 3;
+// default number of successful health checks before a server is marked healthy
+const DEFAULT_HEALTHY_THRESHOLD: u64 = 2;
+// default maximum number of downstream servers tried for a single request
+const DEFAULT_MAX_ATTEMPTS: u64 = 3;
+// default timeout in seconds of a single attempt to a downstream server
+const DEFAULT_REQUEST_TIMEOUT: u64 = 300;
+// default time budget in seconds for all the attempts of a single request
+const DEFAULT_RETRY_BUDGET: u64 = 600;
+// default maximum number of requests waiting for a free downstream server
+const DEFAULT_QUEUE_MAX_DEPTH: usize = 100;
+// default maximum time in seconds a request waits for a free downstream server
+const DEFAULT_QUEUE_MAX_WAIT: u64 = 60;
+// default time in seconds the result of a finished job is kept
+const DEFAULT_JOB_TTL: u64 = 3600;
+// default time in seconds the images in the image store are kept
+const DEFAULT_IMAGE_RETENTION: u64 = 86400;
+// interval at which the queued requests re-check the downstream servers
+const QUEUE_RECHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Parser)]
 #[command(name = "SD-Proxy-Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "SD-Proxy-Server")]
 #[command(group = ArgGroup::new("socket_address_group").multiple(false).args(&["socket_addr", "port"]))]
 struct Cli {
+    /// Path to a TOML or JSON config file. The command line options override the values in the file.
+    #[arg(long)]
+    config: Option<PathBuf>,
     /// Socket address of SD-Proxy-Server instance. For example, `0.0.0.0:8080`.
     #[arg(long, default_value = None, value_parser = clap::value_parser!(SocketAddr), group = "socket_address_group")]
     socket_addr: Option<SocketAddr>,
-    /// Socket address of SD-Proxy-Server instance
-    #[arg(long, default_value = DEFAULT_PORT, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
-    port: u16,
+    /// Port of SD-Proxy-Server instance [default: 8080]
+    #[arg(long, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
+    port: Option<u16>,
+    /// Policy used to pick a downstream server [default: least-connections]
+    #[arg(long, value_enum)]
+    routing_policy: Option<PolicyKind>,
+    /// Interval in seconds between health checks of the downstream servers. Set to 0 to disable health checking. [default: 10]
+    #[arg(long)]
+    health_check_interval: Option<u64>,
+    /// Interval in seconds between two refreshes of the models, samplers, LoRAs, upscalers and ControlNet modules of the downstream servers. Set to 0 to query them only on registration. [default: 300]
+    #[arg(long)]
+    discovery_interval: Option<u64>,
+    /// Timeout in seconds of a single health check [default: 5]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_timeout: Option<u64>,
+    /// Number of consecutive failed health checks before a downstream server is marked unhealthy [default: 3]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    unhealthy_threshold: Option<u64>,
+    /// Number of consecutive successful health checks before an unhealthy downstream server is marked healthy [default: 2]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    healthy_threshold: Option<u64>,
+    /// Maximum number of downstream servers tried for a single request [default: 3]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_attempts: Option<u64>,
+    /// Timeout in seconds of a single attempt to a downstream server [default: 300]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout: Option<u64>,
+    /// Total time budget in seconds for all the attempts of a single request, including the time spent in the queue [default: 600]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    retry_budget: Option<u64>,
+    /// Path to a JSON file that keeps the registered downstream servers across restarts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Token required as `Authorization: Bearer <token>` on the `/admin` endpoints
+    #[arg(long, env = "SD_PROXY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Path to a JSON file of the API keys accepted on the image endpoints. The image endpoints accept anonymous requests if it is not set.
+    #[arg(long)]
+    api_keys_file: Option<PathBuf>,
+    /// Maximum number of image requests per minute across all the clients
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_rpm: Option<u32>,
+    /// Maximum number of image requests in flight across all the clients
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_concurrency: Option<u32>,
+    /// Maximum number of image requests per minute of every API key that does not declare its own limit
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    key_rate_limit_rpm: Option<u32>,
+    /// Maximum number of image requests in flight of every API key that does not declare its own limit
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    key_rate_limit_concurrency: Option<u32>,
+    /// Maximum number of requests in flight on every downstream server that does not declare its own limit. Unlimited if not set. SD WebUI runs one generation at a time, so `1` is a good choice.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrency: Option<u64>,
+    /// Maximum number of requests waiting for a free downstream server [default: 100]
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    queue_max_depth: Option<usize>,
+    /// Maximum time in seconds a request waits for a free downstream server [default: 60]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    queue_max_wait: Option<u64>,
+    /// Time in seconds the result of a finished asynchronous job is kept [default: 3600]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    job_ttl: Option<u64>,
+    /// Respond to the image endpoints with a bare array of images instead of the OpenAI `{"created", "data"}` envelope
+    #[arg(long)]
+    legacy_response: bool,
+    /// Directory of the image store, which keeps the images returned with `response_format: "url"`. The store is disabled if it is not set.
+    #[arg(long)]
+    image_store_dir: Option<PathBuf>,
+    /// Base of the URLs of the stored images, e.g. `https://images.example.com` [default: http://localhost:{port}]
+    #[arg(long)]
+    public_base_url: Option<String>,
+    /// Time in seconds the images in the image store are kept [default: 86400]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    image_retention: Option<u64>,
+    /// What to do with the textual metadata of the returned PNG images, such as the prompt and the seed [default: keep]
+    #[arg(long, value_enum)]
+    png_metadata: Option<MetadataPolicy>,
+    /// Add the generation info (seed, subseed, sampler, steps, cfg_scale and model) to every returned image, as if the requests set `include_info`
+    #[arg(long)]
+    generation_info: bool,
 }
 
 #[allow(clippy::needless_return)]
@@ -64,32 +210,287 @@ async fn main() -> Result<(), ServerError> {
     // log the version of the server
     info!(target: "stdout", "version: {}", env!("CARGO_PKG_VERSION"));
 
+    // load the config file
+    let config = match &cli.config {
+        Some(path) => {
+            info!(target: "stdout", "config file: {}", path.display());
+            Config::load(path)?
+        }
+        None => Config::default(),
+    };
+
     // Create a shared HTTP client
     let client = Arc::new(Client::new());
 
-    let app_state = AppState::new(client);
+    let retry = RetryConfig {
+        max_attempts: cli
+            .max_attempts
+            .or(config.retry.max_attempts)
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS) as usize,
+        request_timeout: Duration::from_secs(
+            cli.request_timeout
+                .or(config.timeouts.request)
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        ),
+        budget: Duration::from_secs(
+            cli.retry_budget
+                .or(config.timeouts.retry_budget)
+                .unwrap_or(DEFAULT_RETRY_BUDGET),
+        ),
+    };
+
+    let image_routing_policy = cli
+        .routing_policy
+        .or(config.backends.routing_policy.image)
+        .or(config.routing_policy)
+        .unwrap_or_default();
+    info!(target: "stdout", "routing policy of the image servers: {}", image_routing_policy);
+
+    let registry_file = cli.state_file.or(config.state_file).map(RegistryFile::new);
+
+    let admin_token = cli.admin_token.or(config.admin_token);
+    if admin_token.is_none() {
+        warn!(target: "stdout", "no admin token is configured, the /admin endpoints are open to everyone");
+    }
+
+    let api_keys = match cli.api_keys_file.or(config.api_keys_file) {
+        Some(path) => Some(ApiKeyStore::load(path)?),
+        None => {
+            warn!(target: "stdout", "no API key file is configured, the image endpoints accept anonymous requests");
+            None
+        }
+    };
+
+    let rate_limiter = RateLimiter::new(
+        RateLimit {
+            requests_per_minute: cli
+                .rate_limit_rpm
+                .or(config.rate_limit.global.requests_per_minute),
+            max_concurrent: cli
+                .rate_limit_concurrency
+                .or(config.rate_limit.global.max_concurrent),
+        },
+        RateLimit {
+            requests_per_minute: cli
+                .key_rate_limit_rpm
+                .or(config.rate_limit.per_key.requests_per_minute),
+            max_concurrent: cli
+                .key_rate_limit_concurrency
+                .or(config.rate_limit.per_key.max_concurrent),
+        },
+    );
+
+    let image_services = Services::new(
+        image_routing_policy,
+        QueueConfig {
+            max_depth: cli
+                .queue_max_depth
+                .or(config.queue.max_depth)
+                .unwrap_or(DEFAULT_QUEUE_MAX_DEPTH),
+            max_wait: Duration::from_secs(
+                cli.queue_max_wait
+                    .or(config.queue.max_wait)
+                    .unwrap_or(DEFAULT_QUEUE_MAX_WAIT),
+            ),
+        },
+        cli.max_concurrency
+            .or(config.queue.server_max_concurrency)
+            .map(|max_concurrency| max_concurrency as usize),
+    );
+
+    // socket address
+    let addr = match (cli.socket_addr, cli.port, config.listen) {
+        (Some(addr), _, _) => addr,
+        (None, Some(port), _) => SocketAddr::from(([0, 0, 0, 0], port)),
+        (None, None, Some(addr)) => addr,
+        (None, None, None) => SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+    };
+
+    let image_retention = Duration::from_secs(
+        cli.image_retention
+            .or(config.image_store.retention)
+            .unwrap_or(DEFAULT_IMAGE_RETENTION),
+    );
+    let image_store: Option<Arc<dyn ImageStore>> = match (
+        cli.image_store_dir.or(config.image_store.dir),
+        &config.image_store.s3,
+    ) {
+        (Some(dir), _) => {
+            let public_base_url = cli
+                .public_base_url
+                .or(config.image_store.public_base_url)
+                .unwrap_or_else(|| {
+                    let public_base_url = format!("http://localhost:{}", addr.port());
+                    warn!(target: "stdout", "no public base url is configured, the image urls start with {}", &public_base_url);
+                    public_base_url
+                });
+
+            let image_store = Arc::new(LocalImageStore::new(
+                dir,
+                &public_base_url,
+                image_retention,
+            )?);
+            storage::spawn_image_sweeper(image_store.clone());
+            Some(image_store)
+        }
+        (None, Some(s3)) => Some(Arc::new(S3ImageStore::new(
+            client.clone(),
+            s3.to_s3_config(image_retention)?,
+        ))),
+        (None, None) => None,
+    };
+
+    let jobs = Arc::new(JobStore::new(Duration::from_secs(
+        cli.job_ttl.or(config.jobs.ttl).unwrap_or(DEFAULT_JOB_TTL),
+    )));
+    jobs::spawn_job_sweeper(jobs.clone());
+
+    let png_metadata = PngMetadata {
+        policy: cli
+            .png_metadata
+            .or(config.output.png_metadata)
+            .unwrap_or_default(),
+        text: config
+            .output
+            .png_metadata_text
+            .clone()
+            .into_iter()
+            .collect(),
+    };
+    if png_metadata.policy == MetadataPolicy::Replace && png_metadata.text.is_empty() {
+        return Err(ServerError::ArgumentError(
+            "`png_metadata` is `replace` but `output.png_metadata_text` is empty".to_string(),
+        ));
+    }
+    info!(target: "stdout", "png metadata: {}", png_metadata.policy);
+
+    let app_state = AppState {
+        client,
+        image_urls: Arc::new(RwLock::new(image_services)),
+        retry: Arc::new(retry),
+        registry_file: registry_file.map(Arc::new),
+        admin_token: admin_token.map(Arc::from),
+        api_keys: api_keys.map(Arc::new),
+        rate_limiter: Arc::new(rate_limiter),
+        jobs,
+        image_store,
+        legacy_response: cli.legacy_response || config.legacy_response.unwrap_or_default(),
+        png_metadata: Arc::new(png_metadata),
+        generation_info: cli.generation_info || config.output.generation_info.unwrap_or_default(),
+        models: Arc::new(ModelCache::default()),
+    };
+
+    // restore the downstream servers registered before the last shutdown
+    app_state.restore_registry().await?;
+
+    // register the downstream servers declared in the config file
+    app_state.register_backends(&config.backends.image).await?;
+
+    {
+        let services = app_state.image_urls.read().await;
+        let interruptible = services.default_max_concurrency == Some(1)
+            || services
+                .servers
+                .read()
+                .await
+                .iter()
+                .any(|s| s.is_interruptible());
+        if !interruptible {
+            warn!(target: "stdout", "no downstream server has max_concurrency = 1, so the cancelled jobs and the abandoned requests are not interrupted on SD WebUI. Set `--max-concurrency 1` to interrupt them");
+        }
+    }
+
+    // start probing the downstream servers
+    health::spawn_health_checker(
+        app_state.clone(),
+        HealthCheckConfig {
+            interval: Duration::from_secs(
+                cli.health_check_interval
+                    .or(config.health_check.interval)
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+            ),
+            timeout: Duration::from_secs(
+                cli.health_check_timeout
+                    .or(config.timeouts.health_check)
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT),
+            ),
+            unhealthy_threshold: cli
+                .unhealthy_threshold
+                .or(config.health_check.unhealthy_threshold)
+                .unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD) as usize,
+            healthy_threshold: cli
+                .healthy_threshold
+                .or(config.health_check.healthy_threshold)
+                .unwrap_or(DEFAULT_HEALTHY_THRESHOLD) as usize,
+        },
+    );
+
+    // keep the capabilities of the downstream servers up to date
+    discovery::spawn_discovery_refresher(
+        app_state.clone(),
+        Duration::from_secs(
+            cli.discovery_interval
+                .or(config.discovery.interval)
+                .unwrap_or(DEFAULT_DISCOVERY_INTERVAL),
+        ),
+    );
 
     // Build our application with routes
-    let app = Router::new()
-        .route("/v1/images/generations", post(image_handler))
-        .route("/v1/images/edits", post(image_handler))
+    let admin_routes = Router::new()
         .route("/admin/register/:type", post(add_url_handler))
         .route("/admin/unregister/:type", post(remove_url_handler))
         .route("/admin/servers", post(list_downstream_servers_handler))
+        .route("/admin/weight/:type", post(set_weight_handler))
+        .route("/admin/policy/:type", post(set_policy_handler))
+        .route("/admin/keys", post(list_api_keys_handler))
+        .route("/admin/keys/add", post(add_api_key_handler))
+        .route("/admin/keys/revoke", post(revoke_api_key_handler))
+        .route("/admin/rate-limits", post(rate_limits_handler))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::admin_auth,
+        ));
+    // the endpoints starting a generation are rate limited
+    let generation_routes = Router::new()
+        .route("/v1/images/generations", post(image_handler))
+        .route("/v1/images/edits", post(image_handler))
+        .route("/v1/jobs", post(create_job_handler))
+        // the last layer runs first: authenticate, then rate limit
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::api_key_auth,
+        ));
+    let image_routes = Router::new()
+        .route("/v1/models", get(list_models_handler))
+        .route(
+            "/v1/jobs/:id",
+            get(get_job_handler).delete(cancel_job_handler),
+        )
+        .route("/v1/jobs/:id/events", get(job_events_handler))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::api_key_auth,
+        ));
+    // the ids of the stored images are random, so the files are served without an API key
+    let file_routes = Router::new().route("/v1/images/files/:id", get(image_file_handler));
+    let app = Router::new()
+        .merge(generation_routes)
+        .merge(image_routes)
+        .merge(file_routes)
+        .merge(admin_routes)
         .with_state(app_state);
 
-    // socket address
-    let addr = match cli.socket_addr {
-        Some(addr) => addr,
-        None => SocketAddr::from(([0, 0, 0, 0], cli.port)),
-    };
     let tcp_listener = TcpListener::bind(addr).await.unwrap();
     info!(target: "stdout", "Listening on {}", addr);
 
     // run
     match axum::Server::from_tcp(tcp_listener.into_std().unwrap())
         .unwrap()
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
     {
         Ok(_) => Ok(()),
@@ -97,9 +498,27 @@ async fn main() -> Result<(), ServerError> {
     }
 }
 
+/// What the routing of a request depends on
+#[derive(Debug, Default)]
+struct RouteContext<'a> {
+    /// Servers already tried by the request
+    excluded: &'a [Uri],
+    /// Checkpoint requested in `override_settings.sd_model_checkpoint`
+    model: Option<&'a str>,
+}
+
 #[async_trait]
 trait RoutingPolicy {
-    async fn next(&self) -> Result<Uri, ServerError>;
+    /// Selects a downstream server that is not in `route.excluded` and reserves an in-flight
+    /// connection slot on it.
+    ///
+    /// If a checkpoint is requested, the servers that have it loaded are preferred, then the
+    /// servers that can load it, then the servers whose checkpoints are unknown. The servers
+    /// known not to serve it are never selected.
+    ///
+    /// If every candidate is running at its maximum concurrency, the request waits in a FIFO
+    /// queue until a slot is released.
+    async fn next(&self, route: &RouteContext<'_>) -> Result<ConnectionGuard, ServerError>;
 }
 
 /// Represents a downstream SD server
@@ -107,83 +526,515 @@ trait RoutingPolicy {
 struct Server {
     url: Uri,
     connections: AtomicUsize,
+    healthy: AtomicBool,
+    consecutive_successes: AtomicUsize,
+    consecutive_failures: AtomicUsize,
+    /// Unix timestamp of the last health check, or 0 if the server has never been probed
+    last_probe: AtomicU64,
+    /// Timeout of a single attempt to this server, overriding `RetryConfig::request_timeout`
+    request_timeout: Option<Duration>,
+    /// Maximum number of requests in flight on this server. `None` is unlimited.
+    max_concurrency: Option<usize>,
+    /// Share of the traffic of the server under the weighted policies, relative to the others
+    weight: AtomicU32,
+    /// Moving average of the response times in microseconds, 0 until the first response
+    latency_ewma: AtomicU64,
+    /// Checkpoints declared at registration
+    declared_models: Vec<String>,
+    /// Whether the server is declared in the config file, which then owns its options instead
+    /// of the state file
+    configured: bool,
+    /// Checkpoints declared or discovered, and the one currently loaded
+    models: std::sync::RwLock<ServerModels>,
+    /// Discovered samplers, LoRAs, upscalers and ControlNet modules
+    capabilities: std::sync::RwLock<Capabilities>,
 }
 impl Server {
     fn new(url: Uri) -> Self {
         Self {
+            request_timeout: None,
+            max_concurrency: None,
+            weight: AtomicU32::new(DEFAULT_WEIGHT),
+            latency_ewma: AtomicU64::new(0),
+            declared_models: Vec::new(),
+            configured: false,
+            models: std::sync::RwLock::new(ServerModels::default()),
+            capabilities: std::sync::RwLock::new(Capabilities::default()),
             url,
             connections: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            consecutive_successes: AtomicUsize::new(0),
+            consecutive_failures: AtomicUsize::new(0),
+            last_probe: AtomicU64::new(0),
+        }
+    }
+
+    /// Declares the checkpoints the server serves.
+    fn with_models(mut self, models: Vec<String>) -> Self {
+        self.models.get_mut().unwrap().available = models.clone();
+        self.declared_models = models;
+        self
+    }
+
+    fn with_weight(self, weight: Option<u32>) -> Self {
+        if let Some(weight) = weight {
+            self.weight.store(weight, Ordering::Relaxed);
+        }
+        self
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Whether the server runs a single generation at a time, which can then be interrupted
+    /// without stopping the generation of another request
+    fn is_interruptible(&self) -> bool {
+        self.max_concurrency == Some(1)
+    }
+
+    fn has_capacity(&self) -> bool {
+        match self.max_concurrency {
+            Some(max_concurrency) => self.connections.load(Ordering::Relaxed) < max_concurrency,
+            None => true,
+        }
+    }
+
+    fn info(&self) -> ServerInfo {
+        let last_probe = self.last_probe.load(Ordering::Relaxed);
+        let latency_ewma = self.latency_ewma();
+        ServerInfo {
+            url: self.url.to_string(),
+            connections: self.connections.load(Ordering::Relaxed),
+            max_concurrency: self.max_concurrency,
+            weight: self.weight(),
+            healthy: self.is_healthy(),
+            last_probe: (last_probe != 0).then_some(last_probe),
+            latency_ewma_ms: (latency_ewma != 0).then_some(latency_ewma / 1000),
+            models: self.models.read().unwrap().clone(),
+            capabilities: self.capabilities.read().unwrap().clone(),
         }
     }
 }
 
-#[derive(Debug, Default)]
+/// Snapshot of a downstream server reported by `/admin/servers`
+#[derive(Debug, Serialize)]
+struct ServerInfo {
+    url: String,
+    connections: usize,
+    max_concurrency: Option<usize>,
+    weight: u32,
+    healthy: bool,
+    last_probe: Option<u64>,
+    /// Moving average of the response times
+    latency_ewma_ms: Option<u64>,
+    models: ServerModels,
+    capabilities: Capabilities,
+}
+
+/// An in-flight connection slot on a downstream server.
+///
+/// The slot is released when the guard is dropped, so holding it for the whole
+/// downstream round trip keeps the connection count accurate on every return path.
+#[derive(Debug)]
+struct ConnectionGuard {
+    server: Arc<Server>,
+    queue: Arc<RequestQueue>,
+}
+impl ConnectionGuard {
+    fn new(server: Arc<Server>, queue: Arc<RequestQueue>) -> Self {
+        server.connections.fetch_add(1, Ordering::Relaxed);
+        Self { server, queue }
+    }
+
+    fn url(&self) -> &Uri {
+        &self.server.url
+    }
+
+    fn server(&self) -> &Server {
+        &self.server
+    }
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.server.connections.fetch_sub(1, Ordering::Relaxed);
+
+        // a slot is free, let the queued requests try again
+        self.queue.wake();
+    }
+}
+
+/// Result of a single attempt to pick a downstream server
+enum Selection {
+    Selected(ConnectionGuard),
+    /// Every candidate is running at its maximum concurrency
+    Busy,
+    /// No healthy candidate is registered
+    Unavailable,
+    /// No healthy candidate serves the requested checkpoint
+    ModelNotServed,
+}
+
+#[derive(Debug)]
 struct Services {
-    servers: RwLock<Vec<Server>>,
+    servers: RwLock<Vec<Arc<Server>>>,
+    /// Routing policy, which can be changed at runtime, and its implementation
+    policy: std::sync::RwLock<(PolicyKind, Box<dyn Balancer>)>,
+    queue: Arc<RequestQueue>,
+    /// Maximum concurrency of the servers that do not declare their own
+    default_max_concurrency: Option<usize>,
 }
 impl Services {
-    async fn push(&mut self, url: Uri) {
-        let server = Server::new(url);
-        self.servers.write().await.push(server)
+    fn new(
+        policy: PolicyKind,
+        queue_config: QueueConfig,
+        default_max_concurrency: Option<usize>,
+    ) -> Self {
+        Self {
+            servers: RwLock::new(Vec::new()),
+            policy: std::sync::RwLock::new((policy, policy.balancer())),
+            queue: Arc::new(RequestQueue::new(queue_config)),
+            default_max_concurrency,
+        }
+    }
+
+    async fn push(&self, mut server: Server) -> Arc<Server> {
+        if server.max_concurrency.is_none() {
+            server.max_concurrency = self.default_max_concurrency;
+        }
+        let server = Arc::new(server);
+        self.servers.write().await.push(server.clone());
+
+        // the new server may take queued requests
+        self.queue.wake();
+
+        server
+    }
+
+    async fn contains(&self, url: &Uri) -> bool {
+        self.servers.read().await.iter().any(|s| &s.url == url)
+    }
+
+    async fn find(&self, url: &Uri) -> Option<Arc<Server>> {
+        self.servers
+            .read()
+            .await
+            .iter()
+            .find(|s| &s.url == url)
+            .cloned()
+    }
+
+    fn policy(&self) -> PolicyKind {
+        self.policy.read().unwrap().0
+    }
+
+    /// Switches to another routing policy. Returns the previous one.
+    fn set_policy(&self, policy: PolicyKind) -> PolicyKind {
+        let mut current = self.policy.write().unwrap();
+        let previous = current.0;
+        *current = (policy, policy.balancer());
+
+        previous
+    }
+
+    async fn set_weight(&self, url: &Uri, weight: u32) -> Result<(), ServerError> {
+        let servers = self.servers.read().await;
+        let server = servers
+            .iter()
+            .find(|s| &s.url == url)
+            .ok_or(ServerError::NotFoundServer)?;
+        server.weight.store(weight, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    async fn select(&self, route: &RouteContext<'_>) -> Selection {
+        let servers = self.servers.read().await;
+        let candidates: Vec<&Arc<Server>> = servers
+            .iter()
+            .filter(|s| s.is_healthy() && !route.excluded.contains(&s.url))
+            .collect();
+        if candidates.is_empty() {
+            return Selection::Unavailable;
+        }
+
+        // keep the servers of the best tier for the requested checkpoint
+        let candidates = match route.model {
+            Some(model) => {
+                let tiers: Vec<(&Arc<Server>, ModelMatch)> = candidates
+                    .into_iter()
+                    .filter_map(|s| s.match_model(model).map(|tier| (s, tier)))
+                    .collect();
+                let best = match tiers.iter().map(|(_, tier)| *tier).min() {
+                    Some(best) => best,
+                    None => return Selection::ModelNotServed,
+                };
+                tiers
+                    .into_iter()
+                    .filter(|(_, tier)| *tier == best)
+                    .map(|(s, _)| s)
+                    .collect()
+            }
+            None => candidates,
+        };
+
+        let candidates: Vec<&Arc<Server>> = candidates
+            .into_iter()
+            .filter(|s| s.has_capacity())
+            .collect();
+        let server = self.policy.read().unwrap().1.pick(&candidates);
+
+        match server {
+            Some(server) => {
+                Selection::Selected(ConnectionGuard::new(server.clone(), self.queue.clone()))
+            }
+            None => Selection::Busy,
+        }
     }
 }
+
 #[async_trait]
 impl RoutingPolicy for Services {
-    async fn next(&self) -> Result<Uri, ServerError> {
-        if self.servers.read().await.is_empty() {
-            return Err(ServerError::NotFoundServer);
+    async fn next(&self, route: &RouteContext<'_>) -> Result<ConnectionGuard, ServerError> {
+        // the requests for the same checkpoint wait in line, regardless of the others which may
+        // be served by other servers
+        let line = route.model.map(discovery::model_key);
+
+        // serve right away unless other requests of the line are already waiting
+        if !self.queue.is_waiting(line.as_deref()) {
+            match self.select(route).await {
+                Selection::Selected(guard) => return Ok(guard),
+                Selection::Unavailable => return Err(ServerError::NotFoundServer),
+                Selection::ModelNotServed => return Err(route.model_not_served()),
+                Selection::Busy => {}
+            }
         }
 
-        let servers = self.servers.read().await;
-        let server = if servers.len() == 1 {
-            servers.first().unwrap()
-        } else {
-            servers
-                .iter()
-                .min_by(|s1, s2| {
-                    s1.connections
-                        .load(Ordering::Relaxed)
-                        .cmp(&s2.connections.load(Ordering::Relaxed))
-                })
-                .unwrap()
-        };
+        let ticket = self.queue.enter(line).ok_or(ServerError::QueueFull)?;
+        let start = Instant::now();
+        let deadline = start + self.queue.max_wait();
+        info!(target: "stdout", "all downstream servers are busy, the request is queued");
 
-        server.connections.fetch_add(1, Ordering::Relaxed);
-        Ok(server.url.clone())
+        loop {
+            let notified = self.queue.notified();
+
+            if ticket.is_head() {
+                match self.select(route).await {
+                    Selection::Selected(guard) => {
+                        self.queue.record_dispatched(start.elapsed());
+                        info!(target: "stdout", "dispatch the queued request to {} after {:?}", guard.url(), start.elapsed());
+                        return Ok(guard);
+                    }
+                    Selection::Unavailable => return Err(ServerError::NotFoundServer),
+                    Selection::ModelNotServed => return Err(route.model_not_served()),
+                    Selection::Busy => {}
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.queue.record_timed_out();
+                return Err(ServerError::QueueTimeout);
+            }
+
+            // also re-check periodically, e.g. for servers recovering from a failed health check
+            let _ = tokio::time::timeout(remaining.min(QUEUE_RECHECK_INTERVAL), notified).await;
+        }
     }
 }
 
+impl RouteContext<'_> {
+    fn model_not_served(&self) -> ServerError {
+        ServerError::ModelNotServed(self.model.unwrap_or_default().to_string())
+    }
+}
+
+/// Options of the failover between downstream servers
+#[derive(Debug, Clone)]
+struct RetryConfig {
+    /// Maximum number of downstream servers tried for a single request
+    max_attempts: usize,
+    /// Timeout of a single attempt
+    request_timeout: Duration,
+    /// Total time budget for all the attempts of a single request
+    budget: Duration,
+}
+
 #[derive(Clone)]
 struct AppState {
     client: SharedClient,
     image_urls: Arc<RwLock<Services>>,
+    retry: Arc<RetryConfig>,
+    registry_file: Option<Arc<RegistryFile>>,
+    /// Token required on the `/admin` endpoints
+    admin_token: Option<Arc<str>>,
+    /// API keys accepted on the image endpoints
+    api_keys: Option<Arc<ApiKeyStore>>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Asynchronous generation jobs
+    jobs: Arc<JobStore>,
+    /// Store of the images returned as URLs
+    image_store: Option<Arc<dyn ImageStore>>,
+    /// Whether the image endpoints respond with a bare array of images
+    legacy_response: bool,
+    /// Policy applied to the metadata of the returned PNG images
+    png_metadata: Arc<PngMetadata>,
+    /// Whether every returned image carries its generation info
+    generation_info: bool,
+    /// Checkpoints listed by `/v1/models`
+    models: Arc<ModelCache>,
 }
 
 impl AppState {
-    fn new(client: SharedClient) -> Self {
-        Self {
-            client,
-            image_urls: Arc::new(RwLock::new(Services::default())),
+    /// Registers the downstream servers recorded in the state file, if any.
+    async fn restore_registry(&self) -> Result<(), ServerError> {
+        let registry_file = match &self.registry_file {
+            Some(registry_file) => registry_file,
+            None => return Ok(()),
+        };
+
+        let snapshot = registry_file.load()?;
+        let services = self.image_urls.read().await;
+        for server in snapshot.image {
+            let url: Uri = server.url.parse().map_err(|_| {
+                ServerError::StateFile(format!(
+                    "invalid url `{}` in {}",
+                    server.url,
+                    registry_file.path().display()
+                ))
+            })?;
+
+            let server = services
+                .push(
+                    Server::new(url.clone())
+                        .with_models(server.models)
+                        .with_weight(server.weight.filter(|weight| *weight > 0)),
+                )
+                .await;
+            discovery::spawn_discovery(self.client.clone(), server);
+            info!(target: "stdout", "restored server url: {}", url);
         }
+
+        Ok(())
     }
 
-    async fn add_url(&self, url_type: UrlType, url: &Uri) -> Result<(), ServerError> {
-        let mut services = match url_type {
-            UrlType::Image => self.image_urls.write().await,
+    /// Rewrites the state file with the downstream servers registered at runtime. The servers of
+    /// the config file are left out, so that the config file stays their source of truth.
+    async fn save_registry(&self) -> Result<(), ServerError> {
+        let registry_file = match &self.registry_file {
+            Some(registry_file) => registry_file,
+            None => return Ok(()),
+        };
+
+        // taken under the lock of the file, so that a concurrent update is never overwritten
+        // by an older snapshot
+        let snapshot = async {
+            let image = self
+                .image_urls
+                .read()
+                .await
+                .servers
+                .read()
+                .await
+                .iter()
+                .filter(|s| !s.configured)
+                .map(|s| RegisteredServer {
+                    url: s.url.to_string(),
+                    models: s.declared_models.clone(),
+                    weight: (s.weight() != DEFAULT_WEIGHT).then(|| s.weight()),
+                })
+                .collect();
+
+            RegistrySnapshot { image }
         };
 
-        services.push(url.clone()).await;
-        info!(target: "stdout", "registered server url: {}", url);
+        registry_file.save(snapshot).await
+    }
+
+    async fn add_url(&self, url_type: UrlType, server: Server) -> Result<(), ServerError> {
+        {
+            let services = match url_type {
+                UrlType::Image => self.image_urls.read().await,
+            };
+
+            info!(target: "stdout", "registered server url: {}, weight: {}", server.url, server.weight());
+            let server = services.push(server).await;
+            discovery::spawn_discovery(self.client.clone(), server);
+        }
+
+        self.save_registry().await
+    }
+
+    /// Changes the weight of a registered server.
+    async fn set_weight(
+        &self,
+        url_type: UrlType,
+        url: &Uri,
+        weight: u32,
+    ) -> Result<(), ServerError> {
+        {
+            let services = match url_type {
+                UrlType::Image => self.image_urls.read().await,
+            };
+
+            services.set_weight(url, weight).await?;
+            info!(target: "stdout", "set the weight of {} to {}", url, weight);
+        }
+
+        self.save_registry().await
+    }
+
+    /// Registers the servers declared in the config file.
+    async fn register_backends(&self, backends: &[BackendConfig]) -> Result<(), ServerError> {
+        for backend in backends {
+            let mut server = Server::new(backend.uri()?)
+                .with_models(backend.models.clone())
+                .with_weight(backend.weight);
+            server.request_timeout = backend.request_timeout.map(Duration::from_secs);
+            server.max_concurrency = backend.max_concurrency.map(|n| n as usize);
+            server.configured = true;
+            self.register_server(UrlType::Image, server).await?;
+        }
 
         Ok(())
     }
 
+    /// Registers a server declared in the config file. A server restored from the state file
+    /// written by an older version is replaced, so that the options of the config file apply.
+    async fn register_server(&self, url_type: UrlType, server: Server) -> Result<(), ServerError> {
+        {
+            let services = match url_type {
+                UrlType::Image => self.image_urls.read().await,
+            };
+
+            if services.contains(&server.url).await {
+                services
+                    .servers
+                    .write()
+                    .await
+                    .retain(|s| s.url != server.url);
+                info!(target: "stdout", "the config file overrides the restored server url: {}", server.url);
+            } else {
+                info!(target: "stdout", "registered server url: {}", server.url);
+            }
+
+            let server = services.push(server).await;
+            discovery::spawn_discovery(self.client.clone(), server);
+        }
+
+        self.save_registry().await
+    }
+
     async fn remove_url(&self, url_type: UrlType, url: &Uri) -> Result<(), ServerError> {
         let services = match &url_type {
             UrlType::Image => &self.image_urls,
         };
 
-        let services = services.write().await;
+        let services = services.read().await;
         let before = services.servers.read().await.len();
         services
             .servers
@@ -199,10 +1050,40 @@ impl AppState {
         // Optionally, log the removal
         info!(target: "stdout", "Removed {} URL: {}", url_type, url);
 
-        Ok(())
+        drop(services);
+        self.save_registry().await
     }
 
-    async fn list_downstream_servers(&self) -> HashMap<String, Vec<String>> {
+    /// Switches the routing policy of a type of servers. Returns the previous policy.
+    async fn set_policy(&self, url_type: UrlType, policy: PolicyKind) -> PolicyKind {
+        let services = match url_type {
+            UrlType::Image => self.image_urls.read().await,
+        };
+
+        let previous = services.set_policy(policy);
+        info!(target: "stdout", "routing policy of the {} servers: {} -> {}", url_type, previous, policy);
+
+        previous
+    }
+
+    async fn policies(&self) -> HashMap<String, PolicyKind> {
+        let mut policies = HashMap::new();
+        policies.insert("image".to_string(), self.image_urls.read().await.policy());
+
+        policies
+    }
+
+    async fn queue_reports(&self) -> HashMap<String, QueueReport> {
+        let mut queues = HashMap::new();
+        queues.insert(
+            "image".to_string(),
+            self.image_urls.read().await.queue.report(),
+        );
+
+        queues
+    }
+
+    async fn list_downstream_servers(&self) -> HashMap<String, Vec<ServerInfo>> {
         let image_servers = self
             .image_urls
             .read()
@@ -211,7 +1092,7 @@ impl AppState {
             .read()
             .await
             .iter()
-            .map(|s| s.url.to_string())
+            .map(|s| s.info())
             .collect();
 
         let mut servers = HashMap::new();
@@ -221,6 +1102,42 @@ impl AppState {
     }
 }
 
+/// Policy used to pick a downstream server
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum PolicyKind {
+    /// Pick the server with the fewest requests in flight
+    #[default]
+    LeastConnections,
+    /// Pick the server with the fewest requests in flight relative to its weight
+    WeightedLeastConnections,
+    /// Cycle through the servers
+    RoundRobin,
+    /// Spread the requests across the servers in proportion to their weights
+    WeightedRoundRobin,
+    /// Pick a server at random
+    Random,
+    /// Pick two servers at random and keep the one with fewer requests in flight
+    #[value(alias = "p2c")]
+    #[serde(alias = "p2c")]
+    PowerOfTwoChoices,
+    /// Pick the server with the lowest moving average of response times, scaled by its load
+    LatencyEwma,
+}
+impl fmt::Display for PolicyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyKind::LeastConnections => write!(f, "least-connections"),
+            PolicyKind::WeightedLeastConnections => write!(f, "weighted-least-connections"),
+            PolicyKind::RoundRobin => write!(f, "round-robin"),
+            PolicyKind::WeightedRoundRobin => write!(f, "weighted-round-robin"),
+            PolicyKind::Random => write!(f, "random"),
+            PolicyKind::PowerOfTwoChoices => write!(f, "power-of-two-choices"),
+            PolicyKind::LatencyEwma => write!(f, "latency-ewma"),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum UrlType {
     Image,
@@ -232,3 +1149,376 @@ impl fmt::Display for UrlType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response,
+    };
+    use std::{convert::Infallible, future::Future};
+
+    /// Builds the state of a proxy without downstream servers, API keys or rate limits.
+    pub(crate) fn app_state(policy: PolicyKind, max_concurrency: Option<usize>) -> AppState {
+        AppState {
+            client: Arc::new(Client::new()),
+            image_urls: Arc::new(RwLock::new(Services::new(
+                policy,
+                QueueConfig {
+                    max_depth: 100,
+                    max_wait: Duration::from_secs(5),
+                },
+                max_concurrency,
+            ))),
+            retry: Arc::new(RetryConfig {
+                max_attempts: 3,
+                request_timeout: Duration::from_secs(5),
+                budget: Duration::from_secs(10),
+            }),
+            registry_file: None,
+            admin_token: None,
+            api_keys: None,
+            rate_limiter: Arc::new(RateLimiter::new(RateLimit::default(), RateLimit::default())),
+            jobs: Arc::new(JobStore::new(Duration::from_secs(60))),
+            image_store: None,
+            legacy_response: false,
+            png_metadata: Arc::new(PngMetadata::default()),
+            generation_info: false,
+            models: Arc::new(ModelCache::default()),
+        }
+    }
+
+    /// Registers a downstream server without discovering its capabilities.
+    pub(crate) async fn add_server(state: &AppState, server: Server) -> Arc<Server> {
+        state.image_urls.read().await.push(server).await
+    }
+
+    /// Starts a downstream server answering every request with `respond`. Returns its url.
+    pub(crate) async fn mock_backend<F, Fut>(respond: F) -> Uri
+    where
+        F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_service = make_service_fn(move |_| {
+            let respond = respond.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let respond = respond.clone();
+                    async move { Ok::<_, Infallible>(respond(req).await) }
+                }))
+            }
+        });
+        let server = hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service);
+        tokio::spawn(server);
+
+        format!("http://{}", addr).parse().unwrap()
+    }
+
+    fn connections(server: &Server) -> usize {
+        server.connections.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn guards_hold_and_release_connection_slots() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let a = add_server(&state, Server::new("http://a:7860".parse().unwrap())).await;
+        let b = add_server(&state, Server::new("http://b:7860".parse().unwrap())).await;
+
+        let services = state.image_urls.read().await;
+        let mut guards = vec![];
+        for _ in 0..6 {
+            guards.push(services.next(&RouteContext::default()).await.unwrap());
+        }
+        assert_eq!((connections(&a), connections(&b)), (3, 3));
+
+        guards.truncate(2);
+        assert_eq!(connections(&a) + connections(&b), 2);
+
+        drop(guards);
+        assert_eq!((connections(&a), connections(&b)), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn least_connections_skips_busy_servers() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let a = add_server(&state, Server::new("http://a:7860".parse().unwrap())).await;
+        let b = add_server(&state, Server::new("http://b:7860".parse().unwrap())).await;
+
+        let services = state.image_urls.read().await;
+        let first = services.next(&RouteContext::default()).await.unwrap();
+        let second = services.next(&RouteContext::default()).await.unwrap();
+        assert_ne!(first.url(), second.url());
+
+        // the server released first takes the next request
+        let released = first.url().clone();
+        drop(first);
+        let third = services.next(&RouteContext::default()).await.unwrap();
+        assert_eq!(third.url(), &released);
+
+        drop((second, third));
+        assert_eq!((connections(&a), connections(&b)), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn excluded_servers_are_not_selected() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let a = add_server(&state, Server::new("http://a:7860".parse().unwrap())).await;
+        add_server(&state, Server::new("http://b:7860".parse().unwrap())).await;
+
+        let services = state.image_urls.read().await;
+        let excluded = [a.url.clone()];
+        let route = RouteContext {
+            excluded: &excluded,
+            model: None,
+        };
+        for _ in 0..3 {
+            let guard = services.next(&route).await.unwrap();
+            assert_ne!(guard.url(), &a.url);
+        }
+        assert_eq!(connections(&a), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_request_takes_the_released_slot() {
+        let state = app_state(PolicyKind::LeastConnections, Some(1));
+        let a = add_server(&state, Server::new("http://a:7860".parse().unwrap())).await;
+
+        let services = state.image_urls.read().await;
+        let first = services.next(&RouteContext::default()).await.unwrap();
+        let route = RouteContext::default();
+        let (second, _) = tokio::join!(services.next(&route), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(first);
+        });
+        let second = second.unwrap();
+        assert_eq!(connections(&a), 1);
+        assert!(services.queue.is_empty());
+
+        drop(second);
+        assert_eq!(connections(&a), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_checkpoint_does_not_block_the_others() {
+        let state = app_state(PolicyKind::LeastConnections, Some(1));
+        add_server(
+            &state,
+            Server::new("http://anime:7860".parse().unwrap()).with_models(vec!["anime".into()]),
+        )
+        .await;
+        let photo = add_server(
+            &state,
+            Server::new("http://photo:7860".parse().unwrap()).with_models(vec!["photo".into()]),
+        )
+        .await;
+
+        let services = state.image_urls.read().await;
+        let anime = RouteContext {
+            excluded: &[],
+            model: Some("anime"),
+        };
+        let busy = services.next(&anime).await.unwrap();
+
+        // another request for the busy checkpoint waits in line
+        let queued = services.next(&anime);
+        tokio::pin!(queued);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut queued)
+            .await
+            .is_err());
+        assert!(!services.queue.is_empty());
+
+        // while the idle server takes the request for its own checkpoint
+        let route = RouteContext {
+            excluded: &[],
+            model: Some("photo"),
+        };
+        let guard = tokio::time::timeout(Duration::from_millis(500), services.next(&route))
+            .await
+            .expect("the request for another checkpoint is blocked")
+            .unwrap();
+        assert_eq!(guard.url(), &photo.url);
+
+        drop(busy);
+        let queued = queued.await.unwrap();
+        assert_eq!(queued.url().host(), Some("anime"));
+        assert!(services.queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn policy_is_swapped_at_runtime() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let a = add_server(&state, Server::new("http://a:7860".parse().unwrap())).await;
+        let b = add_server(&state, Server::new("http://b:7860".parse().unwrap())).await;
+
+        let services = state.image_urls.read().await;
+        let busy = services.next(&RouteContext::default()).await.unwrap();
+        assert_eq!(busy.url(), &a.url);
+
+        // the least connections keep away from the busy server
+        for _ in 0..4 {
+            let guard = services.next(&RouteContext::default()).await.unwrap();
+            assert_eq!(guard.url(), &b.url);
+        }
+
+        assert_eq!(
+            services.set_policy(PolicyKind::RoundRobin),
+            PolicyKind::LeastConnections
+        );
+        assert_eq!(services.policy(), PolicyKind::RoundRobin);
+
+        // while the round-robin cycles through both
+        let mut urls = vec![];
+        for _ in 0..4 {
+            let guard = services.next(&RouteContext::default()).await.unwrap();
+            urls.push(guard.url().clone());
+        }
+        assert_eq!(urls.iter().filter(|url| **url == a.url).count(), 2);
+        assert_eq!(urls.iter().filter(|url| **url == b.url).count(), 2);
+
+        drop(busy);
+        assert_eq!((connections(&a), connections(&b)), (0, 0));
+    }
+
+    fn backend(url: &str, weight: u32, max_concurrency: Option<u64>) -> BackendConfig {
+        BackendConfig {
+            url: url.to_string(),
+            request_timeout: Some(30),
+            max_concurrency,
+            models: vec!["anime".to_string()],
+            weight: Some(weight),
+        }
+    }
+
+    #[tokio::test]
+    async fn config_backends_win_over_the_state_file() {
+        let dir = std::env::temp_dir().join(format!("sd-proxy-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("servers.json");
+        let boot = || async {
+            let mut state = app_state(PolicyKind::LeastConnections, None);
+            state.registry_file = Some(Arc::new(RegistryFile::new(&path)));
+            state.restore_registry().await.unwrap();
+            state
+        };
+        let saved = || {
+            let snapshot = RegistryFile::new(&path).load().unwrap();
+            snapshot
+                .image
+                .into_iter()
+                .map(|s| s.url)
+                .collect::<Vec<_>>()
+        };
+
+        // a state file of an older version kept the config backends
+        std::fs::write(
+            &path,
+            r#"{"image": [{"url": "http://127.0.0.1:1/", "weight": 2}, {"url": "http://127.0.0.1:3/"}]}"#,
+        )
+        .unwrap();
+        let state = boot().await;
+        state
+            .register_backends(&[
+                backend("http://127.0.0.1:1", 5, Some(2)),
+                backend("http://127.0.0.1:2", 1, None),
+            ])
+            .await
+            .unwrap();
+        state
+            .add_url(
+                UrlType::Image,
+                Server::new("http://127.0.0.1:4".parse().unwrap()),
+            )
+            .await
+            .unwrap();
+
+        let servers = state.image_urls.read().await.servers.read().await.clone();
+        let urls: Vec<String> = servers.iter().map(|s| s.url.to_string()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://127.0.0.1:3/",
+                "http://127.0.0.1:1/",
+                "http://127.0.0.1:2/",
+                "http://127.0.0.1:4/"
+            ]
+        );
+        let configured = &servers[1];
+        assert_eq!(configured.weight(), 5);
+        assert_eq!(configured.max_concurrency, Some(2));
+        assert_eq!(configured.request_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(configured.declared_models, ["anime"]);
+        // only the servers registered at runtime are kept
+        assert_eq!(saved(), ["http://127.0.0.1:3/", "http://127.0.0.1:4/"]);
+
+        // restart with a changed config: a new weight, and the second backend removed
+        let state = boot().await;
+        state
+            .register_backends(&[backend("http://127.0.0.1:1", 3, None)])
+            .await
+            .unwrap();
+        let servers = state.image_urls.read().await.servers.read().await.clone();
+        let urls: Vec<String> = servers.iter().map(|s| s.url.to_string()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://127.0.0.1:3/",
+                "http://127.0.0.1:4/",
+                "http://127.0.0.1:1/"
+            ]
+        );
+        assert_eq!(servers[2].weight(), 3);
+        assert_eq!(servers[2].max_concurrency, None);
+        assert_eq!(saved(), ["http://127.0.0.1:3/", "http://127.0.0.1:4/"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_registrations_are_all_saved() {
+        let dir = std::env::temp_dir().join(format!("sd-proxy-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("servers.json");
+        let mut state = app_state(PolicyKind::LeastConnections, None);
+        state.registry_file = Some(Arc::new(RegistryFile::new(&path)));
+
+        let registrations = (1..=20).map(|port| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let url = format!("http://127.0.0.1:{}", port).parse().unwrap();
+                state.add_url(UrlType::Image, Server::new(url)).await
+            })
+        });
+        for registration in futures_util::future::join_all(registrations).await {
+            registration.unwrap().unwrap();
+        }
+        let url = "http://127.0.0.1:7".parse().unwrap();
+        state.remove_url(UrlType::Image, &url).await.unwrap();
+
+        let saved = RegistryFile::new(&path).load().unwrap().image;
+        assert_eq!(saved.len(), 19);
+        assert!(saved.iter().all(|s| s.url != "http://127.0.0.1:7/"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn zero_queue_limits_are_rejected() {
+        for arg in ["--queue-max-depth", "--queue-max-wait"] {
+            assert!(
+                Cli::try_parse_from(["sd-proxy-server", arg, "0"]).is_err(),
+                "{}",
+                arg
+            );
+            assert!(
+                Cli::try_parse_from(["sd-proxy-server", arg, "1"]).is_ok(),
+                "{}",
+                arg
+            );
+        }
+    }
+}
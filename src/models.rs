@@ -0,0 +1,222 @@
+use crate::{
+    discovery::{self, model_key},
+    utils::unix_timestamp,
+    AppState, Server,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Time the model list is served from the cache
+const MODELS_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// A checkpoint in the OpenAI model list, with the number of servers serving it
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ModelObject {
+    /// Name of the checkpoint, usable as `override_settings.sd_model_checkpoint`
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+    /// Number of healthy servers that can load the checkpoint
+    servers: usize,
+    /// Number of healthy servers that have the checkpoint loaded
+    loaded: usize,
+}
+
+/// Response of `/v1/models`
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+/// Model list merged from the checkpoints of every registered server, kept for
+/// `MODELS_CACHE_TTL` so that the servers are not queried on every call
+#[derive(Debug, Default)]
+pub(crate) struct ModelCache {
+    // held while the list is rebuilt, so that concurrent callers wait for a single refresh
+    cached: Mutex<Option<(Instant, Arc<ModelList>)>>,
+}
+impl ModelCache {
+    /// Returns the cached model list, or rebuilds it if it has expired.
+    pub(crate) async fn get(&self, state: &AppState) -> Arc<ModelList> {
+        let mut cached = self.cached.lock().await;
+        if let Some((built_at, models)) = cached.as_ref() {
+            if built_at.elapsed() < MODELS_CACHE_TTL {
+                return models.clone();
+            }
+        }
+
+        let models = Arc::new(build_model_list(state).await);
+        *cached = Some((Instant::now(), models.clone()));
+
+        models
+    }
+}
+
+/// Refreshes the checkpoints of the healthy servers and merges them. The checkpoints are
+/// merged regardless of the hash, the directory and the file extension of their names.
+async fn build_model_list(state: &AppState) -> ModelList {
+    let servers: Vec<Arc<Server>> = state
+        .image_urls
+        .read()
+        .await
+        .servers
+        .read()
+        .await
+        .iter()
+        .filter(|server| server.is_healthy())
+        .cloned()
+        .collect();
+
+    let refreshes = servers
+        .iter()
+        .map(|server| discovery::refresh_models(&state.client, server));
+    futures_util::future::join_all(refreshes).await;
+
+    let created = unix_timestamp();
+    let mut models: BTreeMap<String, ModelObject> = BTreeMap::new();
+    for server in &servers {
+        let server_models = server.models.read().unwrap().clone();
+        let loaded = server_models.loaded.as_deref().map(model_key);
+
+        let mut names = server_models.available;
+        names.extend(server_models.loaded.clone());
+        names.sort_by_key(|name| model_key(name));
+        names.dedup_by_key(|name| model_key(name));
+
+        for name in names {
+            let key = model_key(&name);
+            let model = models.entry(key.clone()).or_insert_with(|| ModelObject {
+                id: model_id(&name).to_string(),
+                object: "model",
+                created,
+                owned_by: "sd-webui",
+                servers: 0,
+                loaded: 0,
+            });
+            model.servers += 1;
+            if loaded.as_ref() == Some(&key) {
+                model.loaded += 1;
+            }
+        }
+    }
+
+    ModelList {
+        object: "list",
+        data: models.into_values().collect(),
+    }
+}
+
+/// Strips the hash from the title of a checkpoint, e.g.
+/// `v1-5-pruned-emaonly.safetensors [6ce0161689]` is listed as `v1-5-pruned-emaonly.safetensors`.
+fn model_id(name: &str) -> &str {
+    match name.rfind(" [") {
+        Some(index) if name.ends_with(']') => &name[..index],
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{add_server, app_state, mock_backend},
+        PolicyKind,
+    };
+    use hyper::{Body, Response, Uri};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Starts a downstream server with its loaded and available checkpoints, counting the
+    /// requests for its checkpoints.
+    async fn sd_backend(loaded: &str, available: &[&str], hits: Arc<AtomicUsize>) -> Uri {
+        let options = serde_json::json!({ "sd_model_checkpoint": loaded }).to_string();
+        let sd_models = serde_json::Value::Array(
+            available
+                .iter()
+                .map(|title| serde_json::json!({ "title": title }))
+                .collect(),
+        )
+        .to_string();
+
+        mock_backend(move |req| {
+            let body = match req.uri().path() {
+                "/sdapi/v1/options" => options.clone(),
+                _ => {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    sd_models.clone()
+                }
+            };
+            async move { Response::new(Body::from(body)) }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn checkpoints_of_the_healthy_servers_are_merged() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let a = sd_backend(
+            "anime.safetensors [31e35c80fc]",
+            &["anime.safetensors [31e35c80fc]", "photo.ckpt [6ce0161689]"],
+            hits.clone(),
+        )
+        .await;
+        let b = sd_backend(
+            "photo.safetensors [0123456789]",
+            &["photo.safetensors [0123456789]"],
+            hits.clone(),
+        )
+        .await;
+        let c = sd_backend("sdxl.safetensors", &["sdxl.safetensors"], hits.clone()).await;
+        add_server(&state, Server::new(a)).await;
+        add_server(&state, Server::new(b)).await;
+        let unhealthy = add_server(&state, Server::new(c)).await;
+        unhealthy
+            .healthy
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+
+        let models = serde_json::to_value(state.models.get(&state).await.as_ref()).unwrap();
+        assert_eq!(models["object"], "list");
+        let data: Vec<(&str, u64, u64)> = models["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|model| {
+                assert_eq!(model["object"], "model");
+                (
+                    model["id"].as_str().unwrap(),
+                    model["servers"].as_u64().unwrap(),
+                    model["loaded"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // the hashes and the extensions differ, the checkpoints are the same
+        assert_eq!(data, [("anime.safetensors", 1, 1), ("photo.ckpt", 2, 1)]);
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn model_list_is_cached_for_its_ttl() {
+        let state = app_state(PolicyKind::LeastConnections, None);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let url = sd_backend("anime", &["anime"], hits.clone()).await;
+        add_server(&state, Server::new(url)).await;
+
+        let first = state.models.get(&state).await;
+        let second = state.models.get(&state).await;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        // once expired, the servers are queried again
+        state.models.cached.lock().await.as_mut().unwrap().0 -= MODELS_CACHE_TTL;
+        let third = state.models.get(&state).await;
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,412 @@
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    ExtendedColorType, ImageEncoder, ImageFormat,
+};
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+/// Quality of the JPEG images if `output_compression` is not set
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+/// Signature at the start of every PNG file
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+/// Chunks holding textual metadata, such as the `parameters` of SD WebUI
+const PNG_TEXT_CHUNKS: [&[u8; 4]; 3] = [b"tEXt", b"iTXt", b"zTXt"];
+
+/// Format the generated images are converted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Png,
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+}
+impl OutputFormat {
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::Webp),
+            _ => Err(format!(
+                "Unsupported `output_format` `{}`. Expected one of `png`, `jpeg` and `webp`",
+                s
+            )),
+        }
+    }
+}
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Png => write!(f, "png"),
+            OutputFormat::Jpeg => write!(f, "jpeg"),
+            OutputFormat::Webp => write!(f, "webp"),
+        }
+    }
+}
+
+/// Re-encodes an image in `format`. Images already in the format are returned as is.
+///
+/// `compression` is the quality of the JPEG images, between 0 and 100. WebP images are
+/// encoded losslessly, and PNG images ignore it.
+pub(crate) fn convert_image(
+    data: &[u8],
+    format: OutputFormat,
+    compression: Option<u8>,
+) -> Result<Vec<u8>, String> {
+    if image::guess_format(data).ok() == Some(format.image_format()) {
+        return Ok(data.to_vec());
+    }
+
+    let image =
+        image::load_from_memory(data).map_err(|e| format!("failed to decode the image: {}", e))?;
+    let (width, height) = (image.width(), image.height());
+
+    let mut buf = vec![];
+    let result = match format {
+        OutputFormat::Png => PngEncoder::new(&mut buf).write_image(
+            image.to_rgba8().as_raw(),
+            width,
+            height,
+            ExtendedColorType::Rgba8,
+        ),
+        // JPEG has no alpha channel
+        OutputFormat::Jpeg => JpegEncoder::new_with_quality(
+            &mut buf,
+            compression.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100),
+        )
+        .write_image(
+            image.to_rgb8().as_raw(),
+            width,
+            height,
+            ExtendedColorType::Rgb8,
+        ),
+        OutputFormat::Webp => WebPEncoder::new_lossless(&mut buf).write_image(
+            image.to_rgba8().as_raw(),
+            width,
+            height,
+            ExtendedColorType::Rgba8,
+        ),
+    };
+    result.map_err(|e| format!("failed to encode the image as {}: {}", format, e))?;
+
+    Ok(buf)
+}
+
+/// What to do with the textual metadata of the PNG images, e.g. the `parameters` chunk of
+/// SD WebUI holding the prompt, the seed and the model
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MetadataPolicy {
+    /// Return the metadata as generated
+    #[default]
+    Keep,
+    /// Remove the metadata
+    Strip,
+    /// Replace the metadata with the configured text
+    Replace,
+}
+impl fmt::Display for MetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataPolicy::Keep => write!(f, "keep"),
+            MetadataPolicy::Strip => write!(f, "strip"),
+            MetadataPolicy::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+/// Metadata policy of the PNG images
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PngMetadata {
+    pub(crate) policy: MetadataPolicy,
+    /// Keywords and texts written by `MetadataPolicy::Replace`
+    pub(crate) text: Vec<(String, String)>,
+}
+impl PngMetadata {
+    /// Applies the policy to an image. Images other than PNG are returned as is: the JPEG and
+    /// WebP conversions already carry no metadata.
+    pub(crate) fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        if self.policy == MetadataPolicy::Keep || !data.starts_with(&PNG_SIGNATURE) {
+            return Ok(data);
+        }
+
+        let mut chunks = png_chunks(&data)?;
+        chunks.retain(|(chunk_type, _)| !PNG_TEXT_CHUNKS.contains(chunk_type));
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (chunk_type, chunk_data) in chunks {
+            // the text chunks go right before the end of the image
+            if chunk_type == b"IEND" && self.policy == MetadataPolicy::Replace {
+                for (keyword, text) in &self.text {
+                    let (text_type, text_data) = text_chunk(keyword, text);
+                    write_png_chunk(&mut png, text_type, &text_data);
+                }
+            }
+            write_png_chunk(&mut png, chunk_type, chunk_data);
+        }
+
+        Ok(png)
+    }
+}
+
+/// Checks a keyword of a PNG text chunk: 1 to 79 printable Latin-1 characters, without
+/// leading, trailing or consecutive spaces.
+pub(crate) fn validate_png_keyword(keyword: &str) -> Result<(), String> {
+    let printable = keyword
+        .chars()
+        .all(|c| matches!(c as u32, 0x20..=0x7e | 0xa1..=0xff));
+    if keyword.is_empty()
+        || keyword.chars().count() > 79
+        || !printable
+        || keyword.starts_with(' ')
+        || keyword.ends_with(' ')
+        || keyword.contains("  ")
+    {
+        return Err(format!(
+            "invalid PNG metadata keyword `{}`: expected 1 to 79 printable Latin-1 characters",
+            keyword
+        ));
+    }
+
+    Ok(())
+}
+
+/// A PNG chunk, as `(type, data)`
+type PngChunk<'a> = (&'a [u8; 4], &'a [u8]);
+
+/// Splits a PNG file into its chunks.
+fn png_chunks(data: &[u8]) -> Result<Vec<PngChunk<'_>>, String> {
+    let mut chunks = vec![];
+    let mut rest = &data[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err("truncated PNG chunk".to_string());
+        }
+        // the length comes from the downstream server, and may overflow `usize` on wasm32
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if length > rest.len() - 12 {
+            return Err("truncated PNG chunk".to_string());
+        }
+
+        let chunk_type: &[u8; 4] = rest[4..8].try_into().unwrap();
+        chunks.push((chunk_type, &rest[8..8 + length]));
+        rest = &rest[12 + length..];
+
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Appends a chunk with its length and CRC to a PNG file.
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// Builds a text chunk: `tEXt` if the text is Latin-1, and `iTXt` holding UTF-8 otherwise.
+fn text_chunk(keyword: &str, text: &str) -> (&'static [u8; 4], Vec<u8>) {
+    let latin1 = |s: &str| s.chars().map(|c| c as u8).collect::<Vec<u8>>();
+
+    let mut data = latin1(keyword);
+    data.push(0);
+    match text.chars().all(|c| (c as u32) <= 0xff) {
+        true => {
+            data.extend(latin1(text));
+            (b"tEXt", data)
+        }
+        false => {
+            // uncompressed, without language tag nor translated keyword
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(text.as_bytes());
+            (b"iTXt", data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A 2x2 PNG with a `tEXt`, an `iTXt` and a `zTXt` chunk, as written by SD WebUI
+    fn png_with_text() -> Vec<u8> {
+        let mut plain = vec![];
+        PngEncoder::new(&mut plain)
+            .write_image(
+                RgbImage::from_pixel(2, 2, Rgb([255, 0, 0])).as_raw(),
+                2,
+                2,
+                ExtendedColorType::Rgb8,
+            )
+            .unwrap();
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (chunk_type, data) in png_chunks(&plain).unwrap() {
+            if chunk_type == b"IEND" {
+                write_png_chunk(&mut png, b"tEXt", b"parameters\0a cute sea otter");
+                write_png_chunk(&mut png, b"iTXt", b"Comment\0\0\0\0\0\xc3\xa9t\xc3\xa9");
+                let mut ztxt = b"Author\0\0".to_vec();
+                ztxt.extend(zlib_stored(b"sd-webui"));
+                write_png_chunk(&mut png, b"zTXt", &ztxt);
+            }
+            write_png_chunk(&mut png, chunk_type, data);
+        }
+        png
+    }
+
+    /// Compresses nothing: a zlib stream holding a single stored block
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in data {
+            a = (a + *byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+
+        let len = data.len() as u16;
+        let mut stream = vec![0x78, 0x01, 0x01];
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(data);
+        stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        stream
+    }
+
+    /// Types of the chunks of a PNG file, after checking their CRCs
+    fn chunk_types(png: &[u8]) -> Vec<String> {
+        assert!(png.starts_with(&PNG_SIGNATURE));
+
+        let mut types = vec![];
+        let mut rest = &png[PNG_SIGNATURE.len()..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[0..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + length..12 + length].try_into().unwrap());
+            assert_eq!(crc32fast::hash(&rest[4..8 + length]), crc);
+
+            types.push(String::from_utf8(rest[4..8].to_vec()).unwrap());
+            rest = &rest[12 + length..];
+        }
+        types
+    }
+
+    fn text_of(png: &[u8], chunk_type: &[u8; 4]) -> Vec<Vec<u8>> {
+        png_chunks(png)
+            .unwrap()
+            .into_iter()
+            .filter(|(t, _)| *t == chunk_type)
+            .map(|(_, data)| data.to_vec())
+            .collect()
+    }
+
+    fn metadata(policy: MetadataPolicy, text: &[(&str, &str)]) -> PngMetadata {
+        PngMetadata {
+            policy,
+            text: text
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn keep_returns_the_png_as_generated() {
+        let png = png_with_text();
+        image::load_from_memory(&png).unwrap();
+
+        let kept = metadata(MetadataPolicy::Keep, &[])
+            .apply(png.clone())
+            .unwrap();
+        assert_eq!(kept, png);
+        assert_eq!(
+            chunk_types(&kept),
+            ["IHDR", "IDAT", "tEXt", "iTXt", "zTXt", "IEND"]
+        );
+    }
+
+    #[test]
+    fn strip_removes_every_text_chunk() {
+        let stripped = metadata(MetadataPolicy::Strip, &[("Software", "ignored")])
+            .apply(png_with_text())
+            .unwrap();
+
+        assert_eq!(chunk_types(&stripped), ["IHDR", "IDAT", "IEND"]);
+        let image = image::load_from_memory(&stripped).unwrap().to_rgb8();
+        assert_eq!(image.get_pixel(1, 1), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn replace_writes_the_configured_text_before_iend() {
+        let replaced = metadata(
+            MetadataPolicy::Replace,
+            &[("Software", "sd-proxy-server"), ("Title", "ラッコ")],
+        )
+        .apply(png_with_text())
+        .unwrap();
+
+        assert_eq!(
+            chunk_types(&replaced),
+            ["IHDR", "IDAT", "tEXt", "iTXt", "IEND"]
+        );
+        assert_eq!(
+            text_of(&replaced, b"tEXt"),
+            [b"Software\0sd-proxy-server".to_vec()]
+        );
+        let mut itxt = b"Title\0\0\0\0\0".to_vec();
+        itxt.extend_from_slice("ラッコ".as_bytes());
+        assert_eq!(text_of(&replaced, b"iTXt"), [itxt]);
+        let image = image::load_from_memory(&replaced).unwrap().to_rgb8();
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn other_formats_are_left_untouched() {
+        let jpeg = convert_image(&png_with_text(), OutputFormat::Jpeg, None).unwrap();
+        let replaced = metadata(MetadataPolicy::Replace, &[("Software", "sd-proxy-server")])
+            .apply(jpeg.clone())
+            .unwrap();
+        assert_eq!(replaced, jpeg);
+    }
+
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        let png = png_with_text();
+        let error = metadata(MetadataPolicy::Strip, &[])
+            .apply(png[..png.len() - 4].to_vec())
+            .unwrap_err();
+        assert_eq!(error, "truncated PNG chunk");
+
+        // a length near `u32::MAX` neither overflows nor panics
+        let mut huge = PNG_SIGNATURE.to_vec();
+        huge.extend_from_slice(&(u32::MAX - 4).to_be_bytes());
+        huge.extend_from_slice(b"tEXt\0\0\0\0\0\0\0\0");
+        assert_eq!(png_chunks(&huge).unwrap_err(), "truncated PNG chunk");
+    }
+
+    #[test]
+    fn keywords_follow_the_png_rules() {
+        assert!(validate_png_keyword("Software").is_ok());
+        assert!(validate_png_keyword("Creation Time").is_ok());
+        for keyword in ["", " Software", "Software ", "Creation  Time", "ソフト"] {
+            assert!(validate_png_keyword(keyword).is_err(), "{}", keyword);
+        }
+        assert!(validate_png_keyword(&"k".repeat(80)).is_err());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::{PolicyKind, Server};
+use hyper::Uri;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Weight of the latest sample in the latency EWMA
+const EWMA_ALPHA: f64 = 0.3;
+
+/// Picks a downstream server among the healthy candidates that have a free slot. Returns
+/// `None` only if there is no candidate.
+pub(crate) trait Balancer: fmt::Debug + Send + Sync {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>>;
+}
+
+impl PolicyKind {
+    /// Creates a balancer implementing the policy, with a fresh state.
+    pub(crate) fn balancer(&self) -> Box<dyn Balancer> {
+        match self {
+            PolicyKind::LeastConnections => Box::new(LeastConnections),
+            PolicyKind::WeightedLeastConnections => Box::new(WeightedLeastConnections),
+            PolicyKind::RoundRobin => Box::new(RoundRobin::default()),
+            PolicyKind::WeightedRoundRobin => Box::new(WeightedRoundRobin::default()),
+            PolicyKind::Random => Box::new(Random),
+            PolicyKind::PowerOfTwoChoices => Box::new(PowerOfTwoChoices),
+            PolicyKind::LatencyEwma => Box::new(LatencyEwma),
+        }
+    }
+}
+
+/// Picks the server with the fewest requests in flight.
+#[derive(Debug)]
+struct LeastConnections;
+impl Balancer for LeastConnections {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        candidates.iter().copied().min_by_key(|s| s.connections())
+    }
+}
+
+/// Picks the server with the fewest requests in flight relative to its weight.
+#[derive(Debug)]
+struct WeightedLeastConnections;
+impl Balancer for WeightedLeastConnections {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        candidates.iter().copied().min_by(|a, b| {
+            // compare the loads after the assignment, so that idle servers are filled by weight
+            let load =
+                |s: &Server, other: &Server| (s.connections() as u64 + 1) * other.weight() as u64;
+            load(a, b).cmp(&load(b, a))
+        })
+    }
+}
+
+/// Cycles through the servers.
+#[derive(Debug, Default)]
+struct RoundRobin {
+    next: AtomicUsize,
+}
+impl Balancer for RoundRobin {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Some(candidates[index])
+    }
+}
+
+/// Picks the servers with the smooth weighted round-robin of nginx, which interleaves the
+/// servers instead of sending bursts to the heaviest one.
+#[derive(Debug, Default)]
+struct WeightedRoundRobin {
+    /// Running weight of every server, by URL
+    current: Mutex<HashMap<Uri, i64>>,
+}
+impl Balancer for WeightedRoundRobin {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        let mut current = self.current.lock().unwrap();
+
+        // every candidate gains its weight, and the one with the highest running weight wins
+        let mut total = 0;
+        let mut selected: Option<(&'a Arc<Server>, i64)> = None;
+        for server in candidates.iter().copied() {
+            let weight = server.weight() as i64;
+            let running = current.entry(server.url.clone()).or_default();
+            *running += weight;
+            total += weight;
+
+            if selected.is_none_or(|(_, best)| *running > best) {
+                selected = Some((server, *running));
+            }
+        }
+
+        // and pays for it with the total weight
+        let (selected, _) = selected?;
+        if let Some(running) = current.get_mut(&selected.url) {
+            *running -= total;
+        }
+
+        Some(selected)
+    }
+}
+
+/// Picks a server uniformly at random.
+#[derive(Debug)]
+struct Random;
+impl Balancer for Random {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(candidates[fastrand::usize(..candidates.len())])
+    }
+}
+
+/// Picks two servers at random, and keeps the one with the fewer requests in flight.
+#[derive(Debug)]
+struct PowerOfTwoChoices;
+impl Balancer for PowerOfTwoChoices {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        if candidates.len() < 2 {
+            return candidates.first().copied();
+        }
+
+        let first = fastrand::usize(..candidates.len());
+        let mut second = fastrand::usize(..candidates.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+
+        let (first, second) = (candidates[first], candidates[second]);
+        match second.connections() < first.connections() {
+            true => Some(second),
+            false => Some(first),
+        }
+    }
+}
+
+/// Picks the server with the lowest expected latency: the moving average of its response
+/// times, scaled by its requests in flight. Servers without a sample are assumed to be as fast
+/// as the fastest one, so that they get tried without being flooded.
+#[derive(Debug)]
+struct LatencyEwma;
+impl Balancer for LatencyEwma {
+    fn pick<'a>(&self, candidates: &[&'a Arc<Server>]) -> Option<&'a Arc<Server>> {
+        let fastest = candidates
+            .iter()
+            .map(|s| s.latency_ewma())
+            .filter(|ewma| *ewma != 0)
+            .min()
+            .unwrap_or(1);
+
+        candidates.iter().copied().min_by_key(|s| {
+            let ewma = match s.latency_ewma() {
+                0 => fastest,
+                ewma => ewma,
+            };
+            (
+                ewma.saturating_mul(s.connections() as u64 + 1),
+                s.connections(),
+            )
+        })
+    }
+}
+
+impl Server {
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Moving average of the response times in microseconds, or 0 without a sample
+    pub(crate) fn latency_ewma(&self) -> u64 {
+        self.latency_ewma.load(Ordering::Relaxed)
+    }
+
+    /// Adds the response time of a request to the moving average.
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros().min(u64::MAX as u128) as u64;
+        let _ = self
+            .latency_ewma
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ewma| {
+                Some(match ewma {
+                    0 => sample.max(1),
+                    _ => (EWMA_ALPHA * sample as f64 + (1.0 - EWMA_ALPHA) * ewma as f64) as u64,
+                })
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server with `connections` requests in flight
+    fn server(connections: usize, weight: u32, latency_ewma: u64) -> Arc<Server> {
+        let server = Server::new(
+            format!("http://{}:7860", fastrand::u64(..))
+                .parse()
+                .unwrap(),
+        );
+        server.connections.store(connections, Ordering::Relaxed);
+        server.weight.store(weight, Ordering::Relaxed);
+        server.latency_ewma.store(latency_ewma, Ordering::Relaxed);
+        Arc::new(server)
+    }
+
+    /// Counts the picks of every server over `n` requests. With `assign`, the picked server
+    /// keeps the request in flight.
+    fn shares(
+        balancer: &dyn Balancer,
+        servers: &[Arc<Server>],
+        n: usize,
+        assign: bool,
+    ) -> Vec<usize> {
+        let candidates: Vec<&Arc<Server>> = servers.iter().collect();
+        let mut shares = vec![0; servers.len()];
+        for _ in 0..n {
+            let picked = balancer.pick(&candidates).unwrap();
+            let index = servers.iter().position(|s| Arc::ptr_eq(s, picked)).unwrap();
+            shares[index] += 1;
+            if assign {
+                picked.connections.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        shares
+    }
+
+    #[test]
+    fn every_policy_needs_a_candidate() {
+        for policy in [
+            PolicyKind::LeastConnections,
+            PolicyKind::WeightedLeastConnections,
+            PolicyKind::RoundRobin,
+            PolicyKind::WeightedRoundRobin,
+            PolicyKind::Random,
+            PolicyKind::PowerOfTwoChoices,
+            PolicyKind::LatencyEwma,
+        ] {
+            assert!(policy.balancer().pick(&[]).is_none(), "{}", policy);
+
+            let only = server(3, 1, 1000);
+            assert!(Arc::ptr_eq(
+                policy.balancer().pick(&[&only]).unwrap(),
+                &only
+            ));
+        }
+    }
+
+    #[test]
+    fn least_connections_picks_the_least_loaded() {
+        let servers = [server(3, 1, 0), server(1, 1, 0), server(2, 1, 0)];
+        assert_eq!(shares(&LeastConnections, &servers, 10, false), [0, 10, 0]);
+
+        // spreads the requests kept in flight
+        let servers = [server(0, 1, 0), server(0, 1, 0), server(0, 1, 0)];
+        assert_eq!(shares(&LeastConnections, &servers, 9, true), [3, 3, 3]);
+    }
+
+    #[test]
+    fn weighted_least_connections_follows_the_weights() {
+        let servers = [server(0, 1, 0), server(0, 3, 0)];
+        assert_eq!(shares(&WeightedLeastConnections, &servers, 8, true), [2, 6]);
+
+        // relative to the weight, 4 requests on a server of weight 4 are a lighter load than 2
+        // on a server of weight 1
+        let servers = [server(2, 1, 0), server(4, 4, 0)];
+        assert_eq!(
+            shares(&WeightedLeastConnections, &servers, 1, false),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn round_robin_splits_evenly() {
+        // regardless of the load
+        let servers = [server(5, 1, 0), server(0, 4, 0), server(1, 1, 0)];
+        assert_eq!(
+            shares(&RoundRobin::default(), &servers, 300, false),
+            [100, 100, 100]
+        );
+    }
+
+    #[test]
+    fn weighted_round_robin_follows_the_weights() {
+        let servers = [server(0, 1, 0), server(0, 2, 0), server(0, 5, 0)];
+        let candidates: Vec<&Arc<Server>> = servers.iter().collect();
+        let wrr = WeightedRoundRobin::default();
+
+        // every cycle of the total weight gives each server its weight
+        for _ in 0..10 {
+            let mut cycle = vec![0; servers.len()];
+            let mut picks = vec![];
+            for _ in 0..8 {
+                let picked = wrr.pick(&candidates).unwrap();
+                let index = servers.iter().position(|s| Arc::ptr_eq(s, picked)).unwrap();
+                cycle[index] += 1;
+                picks.push(index);
+            }
+            assert_eq!(cycle, [1, 2, 5]);
+
+            // interleaved rather than in a burst
+            assert!(
+                !picks.windows(4).any(|w| w.iter().all(|i| *i == 2)),
+                "{:?}",
+                picks
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_round_robin_starts_afresh() {
+        let servers = [server(0, 1, 0), server(0, 3, 0)];
+        let candidates: Vec<&Arc<Server>> = servers.iter().collect();
+        let picks = |balancer: &dyn Balancer, n: usize| {
+            (0..n)
+                .map(|_| balancer.pick(&candidates).unwrap().url.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let first = PolicyKind::WeightedRoundRobin.balancer();
+        let expected = picks(first.as_ref(), 4);
+        picks(first.as_ref(), 1);
+
+        // e.g. after switching the policy back and forth at runtime
+        let second = PolicyKind::WeightedRoundRobin.balancer();
+        assert_eq!(picks(second.as_ref(), 4), expected);
+    }
+
+    #[test]
+    fn random_splits_roughly_evenly() {
+        let servers = [server(0, 1, 0), server(5, 1, 0), server(0, 4, 0)];
+        for share in shares(&Random, &servers, 3000, false) {
+            assert!((800..1200).contains(&share), "{}", share);
+        }
+    }
+
+    #[test]
+    fn power_of_two_choices_prefers_the_least_loaded() {
+        // the idle server wins whenever it is drawn, i.e. 2 times out of 3
+        let servers = [server(5, 1, 0), server(0, 1, 0), server(5, 1, 0)];
+        let idle = shares(&PowerOfTwoChoices, &servers, 3000, false)[1];
+        assert!((1800..2200).contains(&idle), "{}", idle);
+
+        // and always between two servers
+        let servers = [server(5, 1, 0), server(0, 1, 0)];
+        assert_eq!(shares(&PowerOfTwoChoices, &servers, 100, false), [0, 100]);
+    }
+
+    #[test]
+    fn latency_ewma_prefers_the_fastest() {
+        let servers = [server(0, 1, 3000), server(0, 1, 1000), server(0, 1, 2000)];
+        assert_eq!(shares(&LatencyEwma, &servers, 10, false), [0, 10, 0]);
+
+        // unless it is loaded: 4 x 1000 against 1 x 3000
+        let servers = [server(0, 1, 3000), server(3, 1, 1000)];
+        assert_eq!(shares(&LatencyEwma, &servers, 10, false), [10, 0]);
+
+        // with the requests kept in flight, the load follows the speed
+        let servers = [server(0, 1, 1000), server(0, 1, 3000)];
+        assert_eq!(shares(&LatencyEwma, &servers, 8, true), [6, 2]);
+    }
+
+    #[test]
+    fn latency_ewma_treats_new_servers_as_the_fastest() {
+        // tried rather than starved
+        let servers = [server(1, 1, 1000), server(0, 1, 0)];
+        assert_eq!(shares(&LatencyEwma, &servers, 1, false), [0, 1]);
+
+        // without being flooded
+        let servers = [server(0, 1, 1000), server(0, 1, 0)];
+        assert_eq!(shares(&LatencyEwma, &servers, 4, true), [2, 2]);
+    }
+
+    #[test]
+    fn latencies_are_averaged() {
+        let server = server(0, 1, 0);
+        server.record_latency(Duration::from_millis(1));
+        assert_eq!(server.latency_ewma(), 1000);
+        server.record_latency(Duration::from_millis(2));
+        assert_eq!(server.latency_ewma(), 1300);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{jobs::JobStatus, AppState};
+use axum::http::{Response, StatusCode, Uri};
+use hyper::{
+    body::{to_bytes, Sender},
+    Body,
+};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Progress endpoint of SD WebUI, including the preview of the current image
+const PROGRESS_ENDPOINT: &str = "sdapi/v1/progress?skip_current_image=false";
+/// Interval between two progress events
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Timeout of a single progress poll
+const PROGRESS_POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Progress of a job sent to the client
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    id: &'a str,
+    status: JobStatus,
+    /// Fraction of the generation that is done, between 0 and 1
+    progress: f64,
+    /// Estimated number of seconds until the generation is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    steps: Option<u64>,
+    /// Preview of the current image, base64 encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<String>,
+}
+impl<'a> ProgressEvent<'a> {
+    fn new(id: &'a str, status: JobStatus) -> Self {
+        Self {
+            id,
+            status,
+            progress: 0.0,
+            eta: None,
+            step: None,
+            steps: None,
+            preview: None,
+        }
+    }
+
+    /// Fills in the response of the progress endpoint of SD WebUI.
+    fn with_progress(mut self, progress: &serde_json::Value) -> Self {
+        self.progress = progress["progress"].as_f64().unwrap_or_default();
+        self.eta = progress["eta_relative"].as_f64();
+        self.step = progress["state"]["sampling_step"].as_u64();
+        self.steps = progress["state"]["sampling_steps"].as_u64();
+        self.preview = progress["current_image"].as_str().map(str::to_string);
+        self
+    }
+}
+
+/// Builds a Server-Sent Events response that streams the progress of a job until it is done.
+///
+/// A `progress` event is sent every second while the job runs. The stream ends with a `done`
+/// event holding the job and its images, or with an `error` or a `cancelled` event.
+///
+/// If `cancel_on_disconnect` is set, the job is cancelled when the client goes away.
+pub(crate) fn job_events_response(
+    state: AppState,
+    id: String,
+    owner: Option<String>,
+    cancel_on_disconnect: bool,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Err(e) = send_job_events(&state, &id, owner.as_deref(), &mut sender).await {
+            info!(target: "stdout", "stop streaming the events of job {}: {}", &id, e);
+
+            if cancel_on_disconnect {
+                state.jobs.cancel(&id, owner.as_deref());
+            }
+        }
+    });
+
+    // create a response with status code 200. Content-Type is an event stream
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+async fn send_job_events(
+    state: &AppState,
+    id: &str,
+    owner: Option<&str>,
+    sender: &mut Sender,
+) -> Result<(), hyper::Error> {
+    loop {
+        let changed = state.jobs.changed();
+        let snapshot = match state.jobs.snapshot(id, owner) {
+            Some(snapshot) => snapshot,
+            None => {
+                let error = serde_json::json!({
+                    "error": { "message": format!("The job {} does not exist or has expired.", id) }
+                });
+                return send_event(sender, "error", &error).await;
+            }
+        };
+
+        let event = match (snapshot.status, &snapshot.server) {
+            (JobStatus::Succeeded, _) => return send_event(sender, "done", &snapshot.job).await,
+            (JobStatus::Failed, _) => return send_event(sender, "error", &snapshot.job).await,
+            (JobStatus::Cancelled, _) => {
+                return send_event(sender, "cancelled", &snapshot.job).await
+            }
+            // poll the very server the job was dispatched to
+            (JobStatus::Running, Some(server)) => match poll_progress(state, server).await {
+                Some(progress) => {
+                    Some(ProgressEvent::new(id, snapshot.status).with_progress(&progress))
+                }
+                None => None,
+            },
+            (status, _) => Some(ProgressEvent::new(id, status)),
+        };
+        if let Some(event) = event {
+            send_event(sender, "progress", &event).await?;
+        }
+
+        tokio::select! {
+            _ = changed => {}
+            _ = tokio::time::sleep(PROGRESS_POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Sends a single Server-Sent Event.
+async fn send_event(
+    sender: &mut Sender,
+    event: &str,
+    data: &impl Serialize,
+) -> Result<(), hyper::Error> {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    sender
+        .send_data(format!("event: {}\ndata: {}\n\n", event, data).into())
+        .await
+}
+
+/// Reads the progress of the current generation of a downstream server.
+async fn poll_progress(state: &AppState, server: &Uri) -> Option<serde_json::Value> {
+    let uri: Uri = format!(
+        "{}/{}",
+        server.to_string().trim_end_matches('/'),
+        PROGRESS_ENDPOINT
+    )
+    .parse()
+    .ok()?;
+
+    let response = match tokio::time::timeout(PROGRESS_POLL_TIMEOUT, state.client.get(uri)).await {
+        Ok(Ok(response)) if response.status().is_success() => response,
+        Ok(Ok(response)) => {
+            warn!(target: "stdout", "failed to poll the progress of {}: {}", server, response.status());
+            return None;
+        }
+        Ok(Err(e)) => {
+            warn!(target: "stdout", "failed to poll the progress of {}: {}", server, e);
+            return None;
+        }
+        Err(_) => {
+            warn!(target: "stdout", "failed to poll the progress of {}: timed out", server);
+            return None;
+        }
+    };
+
+    let body = to_bytes(response.into_body()).await.ok()?;
+    serde_json::from_slice(&body).ok()
+}
@@ -0,0 +1,191 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Options of the proxy-side request queue
+#[derive(Debug, Clone)]
+pub(crate) struct QueueConfig {
+    /// Maximum number of requests waiting for a free downstream server
+    pub(crate) max_depth: usize,
+    /// Maximum time a request waits for a free downstream server
+    pub(crate) max_wait: Duration,
+}
+
+/// FIFO queue of the requests waiting for a downstream server with free capacity
+///
+/// The requests are served in order among those of the same line, e.g. asking for the same
+/// checkpoint, so a request that only some busy servers can take does not hold up the others.
+#[derive(Debug)]
+pub(crate) struct RequestQueue {
+    config: QueueConfig,
+    state: Mutex<QueueState>,
+    /// Woken up whenever capacity may have become available
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// Tickets in arrival order, with their line
+    waiting: VecDeque<(u64, Option<String>)>,
+    next_ticket: u64,
+    stats: QueueStats,
+}
+
+#[derive(Debug, Default)]
+struct QueueStats {
+    /// Requests that had to wait in the queue
+    queued: u64,
+    /// Requests rejected because the queue was full
+    rejected: u64,
+    /// Requests that gave up after waiting for `max_wait`
+    timed_out: u64,
+    /// Total wait time of the requests that left the queue with a server
+    total_wait: Duration,
+    /// Requests that left the queue with a server
+    dispatched: u64,
+    /// Longest wait time of a request that left the queue with a server
+    max_observed_wait: Duration,
+}
+
+/// Snapshot of a queue reported by `/admin/servers`
+#[derive(Debug, Serialize)]
+pub(crate) struct QueueReport {
+    depth: usize,
+    max_depth: usize,
+    max_wait_ms: u128,
+    queued: u64,
+    rejected: u64,
+    timed_out: u64,
+    avg_wait_ms: u128,
+    max_observed_wait_ms: u128,
+}
+
+impl RequestQueue {
+    pub(crate) fn new(config: QueueConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    pub(crate) fn max_wait(&self) -> Duration {
+        self.config.max_wait
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().waiting.is_empty()
+    }
+
+    /// Whether requests of the line are waiting
+    pub(crate) fn is_waiting(&self, line: Option<&str>) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .any(|(_, waiting)| waiting.as_deref() == line)
+    }
+
+    /// Enters the line of the queue, or returns `None` if the queue is full. The ticket leaves
+    /// the queue when it is dropped.
+    pub(crate) fn enter(self: &Arc<Self>, line: Option<String>) -> Option<QueueTicket> {
+        let mut state = self.state.lock().unwrap();
+        if state.waiting.len() >= self.config.max_depth {
+            state.stats.rejected += 1;
+            return None;
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back((ticket, line.clone()));
+        state.stats.queued += 1;
+
+        Some(QueueTicket {
+            queue: self.clone(),
+            ticket,
+            line,
+        })
+    }
+
+    /// Returns a future that completes the next time capacity may have become available.
+    ///
+    /// The future must be created before checking for capacity so that no wake-up is lost.
+    pub(crate) fn notified(&self) -> tokio::sync::futures::Notified<'_> {
+        self.notify.notified()
+    }
+
+    /// Wakes up the waiting requests, e.g. when an in-flight request has finished.
+    pub(crate) fn wake(&self) {
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn record_dispatched(&self, wait: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.dispatched += 1;
+        state.stats.total_wait += wait;
+        state.stats.max_observed_wait = state.stats.max_observed_wait.max(wait);
+    }
+
+    pub(crate) fn record_timed_out(&self) {
+        self.state.lock().unwrap().stats.timed_out += 1;
+    }
+
+    pub(crate) fn report(&self) -> QueueReport {
+        let state = self.state.lock().unwrap();
+        let stats = &state.stats;
+        QueueReport {
+            depth: state.waiting.len(),
+            max_depth: self.config.max_depth,
+            max_wait_ms: self.config.max_wait.as_millis(),
+            queued: stats.queued,
+            rejected: stats.rejected,
+            timed_out: stats.timed_out,
+            avg_wait_ms: match stats.dispatched {
+                0 => 0,
+                n => stats.total_wait.as_millis() / n as u128,
+            },
+            max_observed_wait_ms: stats.max_observed_wait.as_millis(),
+        }
+    }
+}
+
+/// A place in the `RequestQueue`
+#[derive(Debug)]
+pub(crate) struct QueueTicket {
+    queue: Arc<RequestQueue>,
+    ticket: u64,
+    line: Option<String>,
+}
+impl QueueTicket {
+    /// Whether this ticket is at the head of its line and may take the next free server
+    pub(crate) fn is_head(&self) -> bool {
+        self.queue
+            .state
+            .lock()
+            .unwrap()
+            .waiting
+            .iter()
+            .find(|(_, line)| *line == self.line)
+            .map(|(ticket, _)| *ticket)
+            == Some(self.ticket)
+    }
+}
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.queue
+            .state
+            .lock()
+            .unwrap()
+            .waiting
+            .retain(|(ticket, _)| *ticket != self.ticket);
+
+        // let the next request in line try
+        self.queue.wake();
+    }
+}
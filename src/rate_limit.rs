@@ -0,0 +1,391 @@
+use crate::api_keys::mask_key;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Limits applied to a scope of requests. A `None` limit is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateLimit {
+    /// Maximum number of requests per minute. Bursts of up to this many requests are allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) requests_per_minute: Option<u32>,
+    /// Maximum number of requests in flight at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_concurrent: Option<u32>,
+}
+impl RateLimit {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.requests_per_minute == Some(0) {
+            return Err("`requests_per_minute` must be greater than 0".to_string());
+        }
+        if self.max_concurrent == Some(0) {
+            return Err("`max_concurrent` must be greater than 0".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// A request rejected by the rate limiter
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimitExceeded {
+    /// Human-readable reason
+    pub(crate) message: String,
+    /// Seconds after which the request may succeed
+    pub(crate) retry_after: u64,
+}
+
+/// Token bucket refilled continuously at `requests_per_minute / 60` tokens per second
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    fn new(requests_per_minute: u32) -> Self {
+        Self {
+            capacity: requests_per_minute as f64,
+            tokens: requests_per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Seconds until a token is available, or zero if one is available now.
+    fn wait_time(&mut self) -> u64 {
+        self.refill();
+        match self.tokens >= 1.0 {
+            true => 0,
+            false => ((1.0 - self.tokens) * 60.0 / self.capacity).ceil().max(1.0) as u64,
+        }
+    }
+}
+
+/// Limit and usage of a single scope
+#[derive(Debug)]
+struct Limiter {
+    /// Name of the scope, used in messages and reports
+    name: String,
+    /// Masked API key of the scope, telling apart the keys of a customer in reports
+    key_hint: Option<String>,
+    limit: RateLimit,
+    bucket: Option<TokenBucket>,
+    in_flight: u32,
+    rejected: u64,
+}
+impl Limiter {
+    fn new(name: impl Into<String>, key_hint: Option<String>, limit: RateLimit) -> Self {
+        Self {
+            name: name.into(),
+            key_hint,
+            limit,
+            bucket: limit.requests_per_minute.map(TokenBucket::new),
+            in_flight: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Replaces the limit if it has changed, e.g. after the limit of an API key is updated.
+    fn update_limit(&mut self, limit: RateLimit) {
+        if self.limit != limit {
+            self.bucket = limit.requests_per_minute.map(TokenBucket::new);
+            self.limit = limit;
+        }
+    }
+
+    fn check(&mut self) -> Result<(), RateLimitExceeded> {
+        if let Some(max_concurrent) = self.limit.max_concurrent {
+            if self.in_flight >= max_concurrent {
+                self.rejected += 1;
+                return Err(RateLimitExceeded {
+                    message: format!(
+                        "Rate limit reached for {}: {} concurrent requests.",
+                        self.name, max_concurrent
+                    ),
+                    retry_after: 1,
+                });
+            }
+        }
+
+        if let Some(bucket) = self.bucket.as_mut() {
+            let wait_time = bucket.wait_time();
+            if wait_time > 0 {
+                self.rejected += 1;
+                return Err(RateLimitExceeded {
+                    message: format!(
+                        "Rate limit reached for {}: {} requests per minute.",
+                        self.name, bucket.capacity
+                    ),
+                    retry_after: wait_time,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn acquire(&mut self) {
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+    }
+
+    fn report(&mut self) -> LimiterReport {
+        LimiterReport {
+            name: self.name.clone(),
+            key: self.key_hint.clone(),
+            limit: self.limit,
+            in_flight: self.in_flight,
+            available_tokens: self.bucket.as_mut().map(|bucket| {
+                bucket.refill();
+                bucket.tokens.floor() as u32
+            }),
+            rejected: self.rejected,
+        }
+    }
+}
+
+/// Snapshot of a limiter reported by `/admin/rate-limits`
+#[derive(Debug, Serialize)]
+pub(crate) struct LimiterReport {
+    name: String,
+    /// Masked API key, for the per-key limiters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    limit: RateLimit,
+    in_flight: u32,
+    /// Requests that can be sent right now before the per-minute limit is hit
+    available_tokens: Option<u32>,
+    /// Requests rejected since the proxy started
+    rejected: u64,
+}
+
+#[derive(Debug)]
+struct Limiters {
+    global: Limiter,
+    per_key: HashMap<String, Limiter>,
+}
+
+/// Global and per API key rate limits of the image endpoints
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limiters: Arc<Mutex<Limiters>>,
+    /// Limit of the API keys that do not declare their own
+    default_key_limit: RateLimit,
+}
+impl RateLimiter {
+    pub(crate) fn new(global_limit: RateLimit, default_key_limit: RateLimit) -> Self {
+        Self {
+            limiters: Arc::new(Mutex::new(Limiters {
+                global: Limiter::new("all clients", None, global_limit),
+                per_key: HashMap::new(),
+            })),
+            default_key_limit,
+        }
+    }
+
+    /// Admits a request, or rejects it if the global limit or the limit of its API key is
+    /// reached. The returned permit holds the in-flight slots until it is dropped.
+    ///
+    /// `key` is the API key of the request with its identity and its own limit, if any.
+    pub(crate) fn acquire(
+        &self,
+        key: Option<(&str, &str, Option<RateLimit>)>,
+    ) -> Result<RatePermit, RateLimitExceeded> {
+        let mut limiters = self.limiters.lock().unwrap();
+        let limiters = &mut *limiters;
+
+        let key_limiter = match key {
+            Some((key, name, limit)) => {
+                let limit = limit.unwrap_or(self.default_key_limit);
+                let limiter = limiters
+                    .per_key
+                    .entry(key.to_string())
+                    .or_insert_with(|| Limiter::new(name, Some(mask_key(key)), limit));
+                limiter.update_limit(limit);
+                limiter.check()?;
+                Some(limiter)
+            }
+            None => None,
+        };
+        limiters.global.check()?;
+
+        // both limits passed, take the slots
+        if let Some(limiter) = key_limiter {
+            limiter.acquire();
+        }
+        limiters.global.acquire();
+
+        Ok(RatePermit {
+            limiters: self.limiters.clone(),
+            key: key.map(|(key, _, _)| key.to_string()),
+        })
+    }
+
+    /// Discards the usage of a revoked API key. The requests of the key still in flight only
+    /// release their global slot.
+    pub(crate) fn forget(&self, key: &str) {
+        self.limiters.lock().unwrap().per_key.remove(key);
+    }
+
+    /// Reports the limits and the current usage.
+    pub(crate) fn report(&self) -> serde_json::Value {
+        let mut limiters = self.limiters.lock().unwrap();
+        let mut keys: Vec<LimiterReport> = limiters
+            .per_key
+            .values_mut()
+            .map(|limiter| limiter.report())
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.key.cmp(&b.key)));
+
+        serde_json::json!({
+            "global": limiters.global.report(),
+            "default_key_limit": self.default_key_limit,
+            "keys": keys,
+        })
+    }
+}
+
+/// Admission of a request by the `RateLimiter`. Releases the in-flight slots on drop.
+#[derive(Debug)]
+pub(crate) struct RatePermit {
+    limiters: Arc<Mutex<Limiters>>,
+    key: Option<String>,
+}
+impl Drop for RatePermit {
+    fn drop(&mut self) {
+        let mut limiters = self.limiters.lock().unwrap();
+        limiters.global.in_flight = limiters.global.in_flight.saturating_sub(1);
+        if let Some(limiter) = self
+            .key
+            .as_ref()
+            .and_then(|key| limiters.per_key.get_mut(key))
+        {
+            limiter.in_flight = limiter.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const KEY: &str = "sk-5b0c6d3c1f3e4f1c9d8a2b7e6f5d4f2a";
+
+    fn limit(requests_per_minute: Option<u32>, max_concurrent: Option<u32>) -> RateLimit {
+        RateLimit {
+            requests_per_minute,
+            max_concurrent,
+        }
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let mut bucket = TokenBucket::new(60);
+        bucket.tokens = 0.0;
+        bucket.last_refill -= Duration::from_secs(30);
+        bucket.refill();
+        assert!((29.9..30.1).contains(&bucket.tokens), "{}", bucket.tokens);
+
+        // up to the capacity
+        bucket.last_refill -= Duration::from_secs(3600);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+    #[test]
+    fn retry_after_is_the_time_to_the_next_token() {
+        // a token every 10 seconds
+        let mut bucket = TokenBucket::new(6);
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait_time(), 10);
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.wait_time(), 5);
+        bucket.tokens = 1.0;
+        assert_eq!(bucket.wait_time(), 0);
+
+        // never less than a second
+        let mut bucket = TokenBucket::new(6000);
+        bucket.tokens = 0.99;
+        assert_eq!(bucket.wait_time(), 1);
+    }
+
+    #[test]
+    fn requests_per_minute_are_limited() {
+        let limiter = RateLimiter::new(limit(Some(2), None), RateLimit::default());
+
+        // permits released right away still count against the rate
+        drop(limiter.acquire(None).unwrap());
+        drop(limiter.acquire(None).unwrap());
+        let rejected = limiter.acquire(None).unwrap_err();
+        assert_eq!(rejected.retry_after, 30);
+        assert!(rejected.message.contains("2 requests per minute"));
+        assert_eq!(limiter.report()["global"]["rejected"], 1);
+    }
+
+    #[test]
+    fn concurrent_requests_are_limited_until_released() {
+        let limiter = RateLimiter::new(limit(None, Some(2)), RateLimit::default());
+
+        let first = limiter.acquire(None).unwrap();
+        let _second = limiter.acquire(None).unwrap();
+        let rejected = limiter.acquire(None).unwrap_err();
+        assert_eq!(rejected.retry_after, 1);
+        assert!(rejected.message.contains("2 concurrent requests"));
+        assert_eq!(limiter.report()["global"]["in_flight"], 2);
+
+        drop(first);
+        assert!(limiter.acquire(None).is_ok());
+    }
+
+    #[test]
+    fn keys_are_limited_on_their_own() {
+        let limiter = RateLimiter::new(limit(None, Some(10)), limit(None, Some(1)));
+        let other = "sk-0000000000000000000000000000ffff";
+
+        let _held = limiter.acquire(Some((KEY, "customer-a", None))).unwrap();
+        assert!(limiter.acquire(Some((KEY, "customer-a", None))).is_err());
+        // another key of the same customer, and a key with its own limit
+        let _other = limiter.acquire(Some((other, "customer-a", None))).unwrap();
+        let own = Some(limit(None, Some(2)));
+        assert!(limiter.acquire(Some((KEY, "customer-a", own))).is_ok());
+
+        // the rejections take no global slot
+        let report = limiter.report();
+        assert_eq!(report["global"]["in_flight"], 2);
+        let keys: Vec<&str> = report["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["sk-...4f2a", "sk-...ffff"]);
+        assert!(!report.to_string().contains(KEY));
+    }
+
+    #[test]
+    fn revoked_keys_are_forgotten() {
+        let limiter = RateLimiter::new(limit(None, Some(10)), limit(Some(60), Some(1)));
+        let held = limiter.acquire(Some((KEY, "customer-a", None))).unwrap();
+
+        limiter.forget(KEY);
+        assert_eq!(limiter.report()["keys"], serde_json::json!([]));
+
+        // the request in flight still releases its global slot
+        drop(held);
+        assert_eq!(limiter.report()["global"]["in_flight"], 0);
+    }
+}
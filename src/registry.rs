@@ -0,0 +1,79 @@
+use crate::error::ServerError;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+
+/// Contents of the state file that keeps the downstream servers across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RegistrySnapshot {
+    #[serde(default)]
+    pub(crate) image: Vec<RegisteredServer>,
+}
+
+/// A downstream server recorded in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RegisteredServer {
+    pub(crate) url: String,
+    /// Checkpoints declared at registration
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) models: Vec<String>,
+    /// Weight of the server, if not the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) weight: Option<u32>,
+}
+
+/// The state file of the downstream server registry
+#[derive(Debug)]
+pub(crate) struct RegistryFile {
+    path: PathBuf,
+    // serializes the writers, which take their snapshot while holding it so that the file
+    // always ends with the latest registry
+    lock: Mutex<()>,
+}
+impl RegistryFile {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the registry. A missing file is treated as an empty registry.
+    pub(crate) fn load(&self) -> Result<RegistrySnapshot, ServerError> {
+        if !self.path.exists() {
+            info!(target: "stdout", "state file {} does not exist yet", self.path.display());
+            return Ok(RegistrySnapshot::default());
+        }
+
+        let content = std::fs::read(&self.path).map_err(|e| {
+            ServerError::StateFile(format!("failed to read {}: {}", self.path.display(), e))
+        })?;
+
+        serde_json::from_slice(&content).map_err(|e| {
+            ServerError::StateFile(format!("failed to parse {}: {}", self.path.display(), e))
+        })
+    }
+
+    /// Rewrites the state file with the registry returned by `snapshot`, which is only taken
+    /// once the previous writers are done.
+    pub(crate) async fn save(
+        &self,
+        snapshot: impl Future<Output = RegistrySnapshot>,
+    ) -> Result<(), ServerError> {
+        let _lock = self.lock.lock().await;
+
+        let content = serde_json::to_vec_pretty(&snapshot.await)
+            .map_err(|e| ServerError::StateFile(e.to_string()))?;
+
+        crate::utils::write_atomically(&self.path, &content).map_err(|e| {
+            ServerError::StateFile(format!("failed to write {}: {}", self.path.display(), e))
+        })
+    }
+}
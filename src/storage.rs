@@ -0,0 +1,234 @@
+use crate::error::ServerError;
+use async_trait::async_trait;
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Longest interval between two sweeps of the expired images
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A stored image
+#[derive(Debug)]
+pub(crate) struct StoredImage {
+    pub(crate) content_type: &'static str,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Storage of the images returned with `response_format: "url"`
+#[async_trait]
+pub(crate) trait ImageStore: fmt::Debug + Send + Sync {
+    /// Stores an image under a random id. Returns the absolute URL of the image.
+    async fn save(&self, data: &[u8]) -> Result<String, ServerError>;
+
+    /// Reads an image served by `/v1/images/files/{id}`, or returns `None` if it does not
+    /// exist, has expired or is served from elsewhere.
+    async fn load(&self, id: &str) -> Option<StoredImage>;
+}
+
+/// Generated images written to a local directory under random ids, and deleted after the
+/// retention period
+#[derive(Debug)]
+pub(crate) struct LocalImageStore {
+    dir: PathBuf,
+    /// Base of the URLs of the images, e.g. `https://images.example.com`
+    public_base_url: String,
+    retention: Duration,
+}
+impl LocalImageStore {
+    /// Opens the store, creating the directory if it does not exist yet.
+    pub(crate) fn new(
+        dir: impl Into<PathBuf>,
+        public_base_url: &str,
+        retention: Duration,
+    ) -> Result<Self, ServerError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            ServerError::ArgumentError(format!(
+                "failed to create the image store directory {}: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+        info!(target: "stdout", "image store: {}, public base url: {}, retention: {:?}", dir.display(), public_base_url, retention);
+
+        Ok(Self {
+            dir,
+            public_base_url: public_base_url.trim_end_matches('/').to_string(),
+            retention,
+        })
+    }
+
+    /// Deletes the images older than the retention period.
+    pub(crate) fn purge_expired(&self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(target: "stdout", "failed to read the image store directory {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_image = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_valid_id);
+            if is_image && self.is_expired(&path) && std::fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            info!(target: "stdout", "deleted {} expired images", removed);
+        }
+    }
+
+    fn is_expired(&self, path: &std::path::Path) -> bool {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > self.retention)
+    }
+}
+
+#[async_trait]
+impl ImageStore for LocalImageStore {
+    async fn save(&self, data: &[u8]) -> Result<String, ServerError> {
+        let (id, _) = new_image_id(data);
+        std::fs::write(self.dir.join(&id), data).map_err(|e| {
+            ServerError::Operation(format!("failed to write the image {}: {}", id, e))
+        })?;
+
+        Ok(format!("{}/v1/images/files/{}", self.public_base_url, id))
+    }
+
+    async fn load(&self, id: &str) -> Option<StoredImage> {
+        if !is_valid_id(id) {
+            return None;
+        }
+
+        let path = self.dir.join(id);
+        if self.is_expired(&path) {
+            return None;
+        }
+
+        let data = std::fs::read(&path).ok()?;
+        let content_type = image_format(&data)
+            .map_or("application/octet-stream", |(content_type, _)| content_type);
+
+        Some(StoredImage { content_type, data })
+    }
+}
+
+/// Spawns a task that periodically deletes the expired images.
+pub(crate) fn spawn_image_sweeper(store: Arc<LocalImageStore>) {
+    let interval = store.retention.min(MAX_SWEEP_INTERVAL);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            store.purge_expired();
+        }
+    });
+}
+
+/// Whether `id` may be the id of a stored image. Rejects anything that could escape the
+/// directory of the store.
+fn is_valid_id(id: &str) -> bool {
+    id.starts_with("img-")
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !id.contains("..")
+}
+
+/// Generates a random id for an image, with the file extension of its format. Returns the id
+/// and the content type of the image.
+pub(crate) fn new_image_id(data: &[u8]) -> (String, &'static str) {
+    let (content_type, extension) =
+        image_format(data).unwrap_or(("application/octet-stream", "bin"));
+
+    (
+        format!("img-{}.{}", uuid::Uuid::new_v4().simple(), extension),
+        content_type,
+    )
+}
+
+/// Detects the content type and the file extension of an image from its magic bytes.
+fn image_format(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some(("image/png", "png")),
+        [0xff, 0xd8, 0xff, ..] => Some(("image/jpeg", "jpg")),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+            Some(("image/webp", "webp"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// In-process image store keeping the images in memory, served under `http://images.test`
+    #[derive(Debug, Default)]
+    pub(crate) struct MemoryImageStore {
+        images: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ImageStore for MemoryImageStore {
+        async fn save(&self, data: &[u8]) -> Result<String, ServerError> {
+            let (id, _) = new_image_id(data);
+            self.images
+                .lock()
+                .unwrap()
+                .insert(id.clone(), data.to_vec());
+
+            Ok(format!("http://images.test/v1/images/files/{}", id))
+        }
+
+        async fn load(&self, id: &str) -> Option<StoredImage> {
+            let data = self.images.lock().unwrap().get(id)?.clone();
+            let content_type = image_format(&data)
+                .map_or("application/octet-stream", |(content_type, _)| content_type);
+
+            Some(StoredImage { content_type, data })
+        }
+    }
+
+    #[test]
+    fn ids_cannot_escape_the_store() {
+        assert!(is_valid_id("img-0123abcd.png"));
+        assert!(!is_valid_id("img-../../etc/passwd"));
+        assert!(!is_valid_id("img-..png"));
+        assert!(!is_valid_id("secret.png"));
+    }
+
+    #[tokio::test]
+    async fn local_store_serves_the_saved_images() {
+        let dir = std::env::temp_dir().join(format!("sd-proxy-{}", uuid::Uuid::new_v4().simple()));
+        let store =
+            LocalImageStore::new(&dir, "https://images.example.com/", Duration::from_secs(60))
+                .unwrap();
+
+        let url = store.save(b"\x89PNG\r\n\x1a\n").await.unwrap();
+        let id = url
+            .strip_prefix("https://images.example.com/v1/images/files/")
+            .unwrap();
+        assert!(id.ends_with(".png"));
+
+        let image = store.load(id).await.unwrap();
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(image.data, b"\x89PNG\r\n\x1a\n");
+        assert!(store.load("img-missing.png").await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
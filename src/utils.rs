@@ -64,3 +64,31 @@ impl std::str::FromStr for LogLevel {
         }
     }
 }
+
+/// Returns the current time as seconds since the Unix epoch.
+pub(crate) fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it over `path`, so that
+/// readers never observe a partially written file.
+pub(crate) fn write_atomically(
+    path: impl AsRef<std::path::Path>,
+    content: &[u8],
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        std::io::Write::write_all(&mut file, content)?;
+        file.sync_all()?;
+    }
+
+    std::fs::rename(&tmp_path, path)
+}